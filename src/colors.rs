@@ -0,0 +1,164 @@
+use crate::utils::{Entry, FileType};
+use std::collections::HashMap;
+use std::env;
+use std::os::unix::fs::MetadataExt;
+
+// the types dircolors knows about, with its default colors
+const DEFAULT_TYPES: &str = "di=01;34:ln=01;36:pi=40;33:so=01;35:do=01;35:\
+bd=40;33;01:cd=40;33;01:or=40;31;01:su=37;41:sg=30;43:tw=30;42:ow=34;42:\
+st=37;44:ex=01;32";
+
+// a few common extensions, only used when LS_COLORS is not set
+const DEFAULT_EXTENSIONS: &str = "*.tar=01;31:*.tgz=01;31:*.gz=01;31:\
+*.bz2=01;31:*.xz=01;31:*.zst=01;31:*.zip=01;31:*.7z=01;31:*.deb=01;31:\
+*.rpm=01;31:*.jpg=01;35:*.jpeg=01;35:*.png=01;35:*.gif=01;35:*.svg=01;35:\
+*.mp3=00;36:*.flac=00;36:*.wav=00;36:*.mp4=01;35:*.mkv=01;35";
+
+/*
+    SGR sequences by entry type (`di`, `ln`, ...) and by extension
+    (`*.ext`), in the format of LS_COLORS
+*/
+#[derive(Clone, Debug)]
+pub struct ColorTable {
+    types: HashMap<String, String>,
+    extensions: Vec<(String, String)>, // lower case, later ones win
+}
+
+impl Default for ColorTable {
+    fn default() -> ColorTable {
+        let mut table = ColorTable {
+            types: HashMap::new(),
+            extensions: Vec::new(),
+        };
+        table.apply(DEFAULT_TYPES);
+        table.apply(DEFAULT_EXTENSIONS);
+        table
+    }
+}
+
+impl ColorTable {
+    /*
+        @brief
+            the table of LS_COLORS on top of the default types,
+            the built-in one when it is not set
+    */
+    pub fn from_env() -> ColorTable {
+        match env::var("LS_COLORS") {
+            Ok(spec) if !spec.is_empty() => ColorTable::parse(&spec),
+            _ => ColorTable::default(),
+        }
+    }
+
+    // entries that can't be parsed are skipped, as ls does
+    pub fn parse(spec: &str) -> ColorTable {
+        let mut table = ColorTable {
+            types: HashMap::new(),
+            extensions: Vec::new(),
+        };
+        table.apply(DEFAULT_TYPES);
+        table.apply(spec);
+        table
+    }
+
+    fn apply(&mut self, spec: &str) {
+        for item in spec.split(':') {
+            let (key, value) = match item.split_once('=') {
+                Some(pair) => pair,
+                Option::None => continue,
+            };
+            if let Some(extension) = key.strip_prefix('*') {
+                self.extensions
+                    .push((extension.to_lowercase(), value.to_string()));
+            } else {
+                self.types.insert(key.to_string(), value.to_string());
+            }
+        }
+    }
+
+    fn get_type(&self, key: &str) -> Option<&str> {
+        self.types
+            .get(key)
+            .map(String::as_str)
+            .filter(|code| !code.is_empty() && *code != "0" && *code != "00")
+    }
+
+    /*
+        @brief
+            the color of an entry, by its type first, then by its
+            extension for regular files that are not executable
+    */
+    pub fn get_color(&self, entry: &Entry) -> Option<&str> {
+        let metadata = entry.get_link_metadata()?;
+        if entry.is_broken_link() {
+            return self.get_type("or").or_else(|| self.get_type("ln"));
+        }
+        // ln=target colors links like what they point to
+        if entry.is_symlink() && self.types.get("ln").is_some_and(|code| code == "target") {
+            let target = entry.get_metadata()?;
+            return self.get_kind_color(entry, FileType::from_metadata(target), target.mode());
+        }
+        self.get_kind_color(entry, entry.get_file_type()?, metadata.mode())
+    }
+
+    fn get_kind_color(&self, entry: &Entry, file_type: FileType, mode: u32) -> Option<&str> {
+        match file_type {
+            FileType::Symlink => self.get_type("ln"),
+            FileType::Dir => match (mode & 0o1000 != 0, mode & 0o002 != 0) {
+                (true, true) => self.get_type("tw"),
+                (false, true) => self.get_type("ow"),
+                (true, false) => self.get_type("st"),
+                (false, false) => Option::None,
+            }
+            .or_else(|| self.get_type("di")),
+            FileType::Fifo => self.get_type("pi"),
+            FileType::Socket => self.get_type("so"),
+            FileType::BlockDevice => self.get_type("bd"),
+            FileType::CharDevice => self.get_type("cd"),
+            FileType::Door => self.get_type("do"),
+            FileType::File => self.get_file_color(entry, mode),
+        }
+    }
+
+    fn get_file_color(&self, entry: &Entry, mode: u32) -> Option<&str> {
+        if mode & 0o4000 != 0 {
+            if let Some(code) = self.get_type("su") {
+                return Some(code);
+            }
+        }
+        if mode & 0o2000 != 0 {
+            if let Some(code) = self.get_type("sg") {
+                return Some(code);
+            }
+        }
+        if mode & 0o111 != 0 {
+            if let Some(code) = self.get_type("ex") {
+                return Some(code);
+            }
+        }
+        let name = entry.get_name().to_string_lossy().to_lowercase();
+        self.extensions
+            .iter()
+            .rev()
+            .find(|(extension, _)| name.ends_with(extension.as_str()))
+            .map(|(_, code)| code.as_str())
+            .filter(|code| !code.is_empty())
+            .or_else(|| self.get_type("fi"))
+    }
+}
+
+#[test]
+fn test_fn_parse_ls_colors() {
+    let table = ColorTable::parse("di=00;33:*.RS=01;31:*.rs=04:bogus:ex=");
+    assert_eq!(table.get_type("di"), Some("00;33"));
+    // defaults stay for types LS_COLORS does not mention
+    assert_eq!(table.get_type("ln"), Some("01;36"));
+    // an empty code turns the color off
+    assert_eq!(table.get_type("ex"), Option::None);
+    assert_eq!(
+        table.extensions,
+        vec![
+            (".rs".to_string(), "01;31".to_string()),
+            (".rs".to_string(), "04".to_string())
+        ]
+    );
+}
@@ -1,141 +1,471 @@
-use lazy_static::lazy_static;
-use std::env;
+use crate::colors::ColorTable;
+use crate::sort::{parse_sort_rules, SortKey, SortRule};
+use std::os::unix::fs::MetadataExt;
 use std::path::PathBuf;
-use std::process::exit;
 
-#[macro_use]
-lazy_static! {
-    pub static ref SETTING: Setting = parse_parameter();
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum OutputMode {
+    Tree,
+    Json, // -J
+    Xml,  // -X
+    Html, // -H
 }
 
-pub struct Setting {
-    pub is_all: bool,
-    pub is_dir_only: bool,
-    pub is_no_indentation: bool,
-    pub is_sort_alphanumerically: bool,
-    pub is_sort_mod_time: bool,
-    pub is_unsort: bool,
-    pub is_sort_reverse: bool,
-    pub is_dir_first: bool,
-    pub is_needing_report: bool,
-    pub pattern_p: bool,
-    pub pattern_i: bool,
-    pub is_quote: bool,
-    pub is_full_path: bool,
-    pub pattern_ignore_case: bool,
-    pub is_color: bool,               // -n, -C
-    pub need_protection: bool,        // -p
-    pub need_uid: bool,               // -u
-    pub need_gid: bool,               // -g
-    pub need_size: u8,                // -s, -h, --si
-    pub need_ctime: bool,             // -D
-    pub need_inode: bool,             // --inodes
-    pub need_device: bool,            // --device
-    pub is_level_limited: bool,       // -L
-    pub is_file_number_limited: bool, // --filelimit
-
-    pub pattern: String,
-    pub level: String,
-    pub max_files: String,
-    pub root: PathBuf,
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum SizeFormat {
+    Bytes,  // -s
+    Binary, // -h, powers of 1024
+    Si,     // --si, powers of 1000
 }
 
-impl Default for Setting {
-    fn default() -> Setting {
-        Setting {
+/*
+    why `Options::from_args` gives no options, the caller prints the
+    help or the version and exits
+*/
+#[derive(PartialEq, Clone, Debug)]
+pub enum ArgsError {
+    Help,            // --help
+    Version,         // --version
+    Invalid(String), // a hint at what is wrong
+}
+
+// which timestamp -D shows
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum TimeField {
+    Modified, // by default
+    Accessed, // --time=atime
+    Changed,  // -c, --time=ctime
+    Birth,    // --time=birth
+}
+
+// how characters that can't be printed in a file name are shown
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum NameFormat {
+    Escaped,  // as `\ooo`, by default
+    Question, // -q, as `?`
+    Raw,      // -N, the bytes as they are
+}
+
+/*
+    everything that changes what is listed and how it is printed.
+    Build it with `Options::new(root)` and the setters below, or
+    from command line arguments with `Options::from_args`.
+*/
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub(crate) is_all: bool,
+    pub(crate) is_dir_only: bool,
+    pub(crate) is_following_links: bool, // -l
+    pub(crate) is_one_filesystem: bool,  // -x
+    pub(crate) is_no_indentation: bool,
+    pub(crate) sort_rules: Vec<SortRule>, // -v, -t, -c, -U, --sort
+    pub(crate) is_sort_reverse: bool,
+    pub(crate) is_dir_first: bool,
+    pub(crate) is_needing_report: bool,
+    pub(crate) is_quote: bool,
+    pub(crate) name_format: NameFormat, // -q, -N
+    pub(crate) is_full_path: bool,
+    pub(crate) is_classify: bool, // -F
+    pub(crate) pattern_ignore_case: bool,
+    pub(crate) is_match_dirs: bool,           // --matchdirs
+    pub(crate) is_prune: bool,                // --prune
+    pub(crate) use_gitignore: bool,           // --gitignore
+    pub(crate) is_showing_ignored: bool,      // --show-ignored
+    pub(crate) is_color: bool,                // -n, -C
+    pub(crate) colors: ColorTable,            // LS_COLORS
+    pub(crate) need_protection: bool,         // -p
+    pub(crate) need_uid: bool,                // -u
+    pub(crate) need_gid: bool,                // -g
+    pub(crate) is_numeric_ids: bool,          // --numeric-ids
+    pub(crate) need_size: Option<SizeFormat>, // -s, -h, --si
+    pub(crate) need_time: bool,               // -D, --timefmt
+    pub(crate) time_field: TimeField,         // -c, --time
+    pub(crate) time_format: Option<String>,   // --timefmt
+    pub(crate) need_inode: bool,              // --inodes
+    pub(crate) need_device: bool,             // --device
+    pub(crate) max_level: Option<usize>,      // -L
+    pub(crate) is_rerun: bool,                // -R
+    pub(crate) file_limit: Option<usize>,     // --filelimit
+    pub(crate) threads: usize,                // --threads, 1 reads serially
+    pub(crate) output_mode: OutputMode,       // -J, -X, -H
+    pub(crate) is_no_links: bool,             // --nolinks
+
+    pub(crate) include_patterns: Vec<String>, // -P
+    pub(crate) exclude_patterns: Vec<String>, // -I
+    pub(crate) base_href: String,
+    pub(crate) html_title: String,
+    pub(crate) output_file: Option<PathBuf>, // -o
+    pub(crate) root: PathBuf,
+}
+
+/*
+    what a walk reads while running: the options, and what is
+    worked out from them once before it starts
+*/
+#[derive(Debug)]
+pub struct Context {
+    pub options: Options,
+    pub root_prefix: PathBuf, // stripped from paths of entries
+    pub root_device: u64,     // for -x
+}
+
+impl Context {
+    pub fn new(options: Options) -> Context {
+        let root_device = if options.is_one_filesystem {
+            options.root.metadata().map(|data| data.dev()).unwrap_or(0)
+        } else {
+            0
+        };
+        Context {
+            root_prefix: options.get_root_prefix(),
+            root_device,
+            options,
+        }
+    }
+}
+
+impl Options {
+    pub fn new(root: PathBuf) -> Options {
+        // "." and the like have no name to print the root entry with
+        let root = root.canonicalize().unwrap_or(root);
+        Options {
             is_all: false,
             is_dir_only: false,
+            is_following_links: false,
+            is_one_filesystem: false,
             is_no_indentation: false,
-            is_sort_alphanumerically: true,
-            is_sort_mod_time: false,
-            is_unsort: false,
+            sort_rules: Options::single_sort_rule(SortKey::Name),
             is_sort_reverse: false,
             is_dir_first: false,
             is_needing_report: true,
             is_quote: false,
+            name_format: NameFormat::Escaped,
             is_color: false,
+            colors: ColorTable::default(),
             is_full_path: false,
-            pattern_i: false,
-            pattern_p: false,
+            is_classify: false,
             pattern_ignore_case: false,
+            is_match_dirs: false,
+            is_prune: false,
+            use_gitignore: false,
+            is_showing_ignored: false,
             need_protection: false,
             need_uid: false,
             need_gid: false,
-            need_size: 0,
-            need_ctime: false,
+            is_numeric_ids: false,
+            need_size: Option::None,
+            need_time: false,
+            time_field: TimeField::Modified,
+            time_format: Option::None,
             need_inode: false,
             need_device: false,
-            is_level_limited: false,
-            is_file_number_limited: false,
-            pattern: String::new(),
-            level: String::new(),
-            max_files: String::new(),
-            root: PathBuf::new(),
+            max_level: Option::None,
+            is_rerun: false,
+            file_limit: Option::None,
+            threads: 1,
+            output_mode: OutputMode::Tree,
+            is_no_links: false,
+            include_patterns: Vec::new(),
+            exclude_patterns: Vec::new(),
+            base_href: String::new(),
+            html_title: String::from("Directory Tree"),
+            output_file: Option::None,
+            root,
         }
     }
-}
 
-impl Setting {
-    pub fn get_root() -> PathBuf {
-        SETTING.root.clone()
+    /* ------- listing options ------- */
+
+    // -a
+    pub fn all(mut self, yes: bool) -> Options {
+        self.is_all = yes;
+        self
     }
 
-    pub fn get_root_prefix() -> PathBuf {
-        match SETTING.root.parent() {
-            Some(path) => path.to_path_buf().clone(),
-            _ => PathBuf::from("/"),
-        }
+    // -d
+    pub fn dirs_only(mut self, yes: bool) -> Options {
+        self.is_dir_only = yes;
+        self
     }
 
-    pub fn get_pattern() -> Option<(char, String, bool)> {
-        if !(SETTING.pattern_p || SETTING.pattern_i) {
-            return Option::None;
-        } else {
-            return Some((
-                if SETTING.pattern_p { 'P' } else { 'I' },
-                if SETTING.pattern_ignore_case {
-                    SETTING.pattern.to_lowercase()
-                } else {
-                    SETTING.pattern.clone()
-                },
-                SETTING.pattern_ignore_case,
-            ));
-        }
+    // -l
+    pub fn follow_links(mut self, yes: bool) -> Options {
+        self.is_following_links = yes;
+        self
     }
 
-    pub fn get_level() -> i32 {
-        if SETTING.is_level_limited == false {
-            return -1;
-        }
-        return SETTING
-            .level
-            .parse::<i32>()
-            .expect("invalid number after -L");
+    // -x
+    pub fn one_filesystem(mut self, yes: bool) -> Options {
+        self.is_one_filesystem = yes;
+        self
+    }
+
+    // -f
+    pub fn full_path(mut self, yes: bool) -> Options {
+        self.is_full_path = yes;
+        self
+    }
+
+    // -L, `None` for no limit
+    pub fn max_level(mut self, level: Option<usize>) -> Options {
+        self.max_level = level;
+        self
+    }
+
+    // -R
+    pub fn rerun(mut self, yes: bool) -> Options {
+        self.is_rerun = yes;
+        self
+    }
+
+    // -P, can be given many times
+    pub fn include(mut self, pattern: &str) -> Options {
+        self.include_patterns.push(pattern.to_string());
+        self
+    }
+
+    // -I, can be given many times
+    pub fn exclude(mut self, pattern: &str) -> Options {
+        self.exclude_patterns.push(pattern.to_string());
+        self
+    }
+
+    // --ignore-case
+    pub fn ignore_case(mut self, yes: bool) -> Options {
+        self.pattern_ignore_case = yes;
+        self
+    }
+
+    // --matchdirs
+    pub fn match_dirs(mut self, yes: bool) -> Options {
+        self.is_match_dirs = yes;
+        self
+    }
+
+    // --noreport turns it off
+    pub fn report(mut self, yes: bool) -> Options {
+        self.is_needing_report = yes;
+        self
+    }
+
+    // --prune
+    pub fn prune(mut self, yes: bool) -> Options {
+        self.is_prune = yes;
+        self
+    }
+
+    // --gitignore
+    pub fn gitignore(mut self, yes: bool) -> Options {
+        self.use_gitignore = yes;
+        self
+    }
+
+    // --show-ignored
+    pub fn show_ignored(mut self, yes: bool) -> Options {
+        self.is_showing_ignored = yes;
+        self
+    }
+
+    // --filelimit
+    pub fn file_limit(mut self, limit: Option<usize>) -> Options {
+        self.file_limit = limit;
+        self
+    }
+
+    // --threads, at least 1
+    pub fn threads(mut self, threads: usize) -> Options {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /* ------- file options ------- */
+
+    // -Q
+    pub fn quote(mut self, yes: bool) -> Options {
+        self.is_quote = yes;
+        self
+    }
+
+    // -q, -N
+    pub fn name_format(mut self, format: NameFormat) -> Options {
+        self.name_format = format;
+        self
+    }
+
+    // -p
+    pub fn protection(mut self, yes: bool) -> Options {
+        self.need_protection = yes;
+        self
+    }
+
+    // -u
+    pub fn uid(mut self, yes: bool) -> Options {
+        self.need_uid = yes;
+        self
+    }
+
+    // -g
+    pub fn gid(mut self, yes: bool) -> Options {
+        self.need_gid = yes;
+        self
+    }
+
+    // --numeric-ids
+    pub fn numeric_ids(mut self, yes: bool) -> Options {
+        self.is_numeric_ids = yes;
+        self
+    }
+
+    // -s, -h, --si
+    pub fn size(mut self, format: Option<SizeFormat>) -> Options {
+        self.need_size = format;
+        self
+    }
+
+    // -D
+    pub fn date(mut self, yes: bool) -> Options {
+        self.need_time = yes;
+        self
+    }
+
+    // -c, --time
+    pub fn time_field(mut self, field: TimeField) -> Options {
+        self.time_field = field;
+        self
+    }
+
+    // --timefmt, also turns on -D
+    pub fn time_format(mut self, format: &str) -> Options {
+        self.time_format = Some(format.to_string());
+        self.need_time = true;
+        self
+    }
+
+    // -F
+    pub fn classify(mut self, yes: bool) -> Options {
+        self.is_classify = yes;
+        self
+    }
+
+    // --inodes
+    pub fn inode(mut self, yes: bool) -> Options {
+        self.need_inode = yes;
+        self
+    }
+
+    // --device
+    pub fn device(mut self, yes: bool) -> Options {
+        self.need_device = yes;
+        self
+    }
+
+    /* ------- sorting options ------- */
+
+    // -v, -t, -c, -U and --sort, empty to keep the directory order
+    pub fn sort(mut self, rules: Vec<SortRule>) -> Options {
+        self.sort_rules = rules;
+        self
+    }
+
+    // -r
+    pub fn reverse(mut self, yes: bool) -> Options {
+        self.is_sort_reverse = yes;
+        self
+    }
+
+    // --dirsfirst
+    pub fn dirs_first(mut self, yes: bool) -> Options {
+        self.is_dir_first = yes;
+        self
+    }
+
+    /* ------- graphics options ------- */
+
+    // -i turns it off
+    pub fn indentation(mut self, yes: bool) -> Options {
+        self.is_no_indentation = !yes;
+        self
+    }
+
+    // -C, -n
+    pub fn color(mut self, yes: bool) -> Options {
+        self.is_color = yes;
+        self
     }
 
-    pub fn get_max_files() -> u32 {
-        assert!(SETTING.is_file_number_limited);
-        return SETTING
-            .max_files
-            .parse::<u32>()
-            .expect("invalid number after --filelimit");
+    // colors in the format of LS_COLORS, over the default ones
+    pub fn ls_colors(mut self, spec: &str) -> Options {
+        self.colors = ColorTable::parse(spec);
+        self
     }
 
-    fn error_report(hint: String) {
-        Setting::print_help();
+    /* ------- XML/HTML/JSON options ------- */
+
+    // -J, -X, -H
+    pub fn output(mut self, mode: OutputMode) -> Options {
+        self.output_mode = mode;
+        self
+    }
+
+    // -H
+    pub fn base_href(mut self, href: &str) -> Options {
+        self.base_href = href.to_string();
+        self
+    }
+
+    // -T
+    pub fn title(mut self, title: &str) -> Options {
+        self.html_title = title.to_string();
+        self
+    }
 
-        // print hint
-        println!("{}", hint);
-        exit(0);
+    // --nolinks
+    pub fn links(mut self, yes: bool) -> Options {
+        self.is_no_links = !yes;
+        self
+    }
+
+    // -o, `print_tree` writes to stdout without it
+    pub fn output_file(mut self, path: Option<PathBuf>) -> Options {
+        self.output_file = path;
+        self
+    }
+
+    // see `parse_parameter`
+    pub fn from_args(args: Vec<String>) -> Result<Options, ArgsError> {
+        parse_parameter(args)
+    }
+
+    pub fn get_root(&self) -> PathBuf {
+        self.root.clone()
+    }
+
+    pub fn get_root_prefix(&self) -> PathBuf {
+        match self.root.parent() {
+            Some(path) => path.to_path_buf().clone(),
+            _ => PathBuf::from("/"),
+        }
     }
 
-    fn print_help() {
+    fn single_sort_rule(key: SortKey) -> Vec<SortRule> {
+        vec![SortRule {
+            key,
+            is_descending: false,
+        }]
+    }
+
+    pub fn print_version() {
+        println!("wtree, version 0.3.0 by @waynexia");
+    }
+
+    pub fn print_help() {
         println! {"
 usage: tree [-acdfghilnpqrstuvxACDFJQNSUX] [-H baseHREF] [-T title ]
 [-L level [-R]] [-P pattern] [-I pattern] [-o filename] [--version]
 [--help] [--inodes] [--device] [--noreport] [--nolinks] [--dirsfirst]
-[--charset charset] [--filelimit[=]#] [--si] [--timefmt[=]<f>]
-[--sort[=]<name>] [--matchdirs] [--ignore-case] [--fromfile] [--]
+[--level[=]#] [--charset charset] [--filelimit[=]#] [--si] [--timefmt[=]<f>]
+[--sort[=]<name>] [--matchdirs] [--prune] [--gitignore] [--show-ignored]
+[--ignore-case] [--numeric-ids] [--threads[=]#] [--time[=]<name>] [--fromfile] [--]
 [<directory list>]
   ------- Listing options -------
   -a            All files are listed.
@@ -143,16 +473,20 @@ usage: tree [-acdfghilnpqrstuvxACDFJQNSUX] [-H baseHREF] [-T title ]
   -l            Follow symbolic links like directories.
   -f            Print the full path prefix for each file.
   -x            Stay on current filesystem only.
-  -L level      Descend only level directories deep.
+  -L level      Descend only level directories deep (also --level=#).
   -R            Rerun tree when max dir level reached.
   -P pattern    List only those files that match the pattern given.
   -I pattern    Do not list files that match the given pattern.
   --ignore-case Ignore case when pattern matching.
   --matchdirs   Include directory names in -P pattern matching.
   --noreport    Turn off file/directory count at end of tree listing.
+  --prune       Prune empty directories from the output.
+  --gitignore   Filter out files ignored by .gitignore files.
+  --show-ignored Show ignored files dimmed instead, with --gitignore.
   --charset X   Use charset X for terminal/HTML and indentation line output.
   --filelimit # Do not descend dirs with more than # files in them.
   --timefmt <f> Print and format time according to the format <f>.
+  --threads #   Read directories with # threads, output order is kept.
   -o filename   Output to file instead of stdout.
   ------- File options -------
   -q            Print non-printable characters as '?'.
@@ -161,10 +495,12 @@ usage: tree [-acdfghilnpqrstuvxACDFJQNSUX] [-H baseHREF] [-T title ]
   -p            Print the protections for each file.
   -u            Displays file owner or UID number.
   -g            Displays file group owner or GID number.content
+  --numeric-ids Print numeric user and group IDs instead of names.
   -s            Print the size in bytes of each file.content
   -h            Print the size in a more human readable way.
   --si          Like -h, but use in SI units (powers of 1000).
   -D            Print the date of last modification or (-c) status change.
+  --time X      Print atime, mtime, ctime or birth time with -D.
   -F            Appends '/', '=', '*', '@', '|' or '>' as per ls -F.
   --inodes      Print inode number of each file.
   --device      Print device ID number to which each file belongs.
@@ -175,7 +511,8 @@ usage: tree [-acdfghilnpqrstuvxACDFJQNSUX] [-H baseHREF] [-T title ]
   -U            Leave files unsorted.
   -r            Reverse the order of the sort.
   --dirsfirst   List directories before files (-U disables).
-  --sort X      Select sort: name,version,size,mtime,ctime.
+  --sort X      Select sort: name,version,size,mtime,ctime,extension,type,none.
+                Keys can be combined as `type,-mtime,name`, `-` for descending.
   ------- Graphics options -------
   -i            Don't print indentation lines.
   -A            Print ANSI lines graphic indentation lines.
@@ -198,172 +535,250 @@ usage: tree [-acdfghilnpqrstuvxACDFJQNSUX] [-H baseHREF] [-T title ]
     }
 }
 
-fn need_extra_para(flag: &String) -> bool {
+fn need_extra_para(flag: &str) -> bool {
     flag.eq("H")
         || flag.eq("T")
         || flag.eq("L")
         || flag.eq("P")
         || flag.eq("I")
         || flag.eq("o")
+        || flag.eq("level")
         || flag.eq("charset")
         || flag.eq("filelimit")
+        || flag.eq("threads")
         || flag.eq("timefmt")
+        || flag.eq("time")
         || flag.eq("sort")
 }
 
 /*
     @brief
-        insert into decomposed args,
-        check for flags that need extra parameter
+        split args into single flags, each followed by its parameter if any,
+        and the directory. Parameters can be given as `-L 2`, `-L2`,
+        `--level 2` or `--level=2`. The directory is the first arg that is
+        no option nor parameter, or the one after `--`.
 */
-fn vec_push_hook(
-    vec: &mut Vec<String>,
-    item: &String,
-    expect_pattern: &mut bool,
-) -> Result<(), ()> {
-    if *expect_pattern == true {
-        return Err(());
-    }
-
-    if need_extra_para(item) {
-        *expect_pattern = true;
-    }
-
-    vec.push(item.clone());
-    Ok(())
-}
-
-fn decompose_arg(args: &Vec<String>) -> Result<Vec<String>, ()> {
+fn decompose_arg(args: &[String]) -> Result<(Vec<String>, Option<String>), ()> {
     let mut expect_pattern = false;
     let mut ret_args: Vec<String> = Vec::new();
+    let mut directory: Option<String> = Option::None;
 
-    for arg in args {
-        if arg.starts_with("--") {
-            if let Some(substr) = arg.get(2..) {
-                vec_push_hook(&mut ret_args, &substr.to_string(), &mut expect_pattern)?
-            };
-        } else if arg.starts_with("-") {
-            if let Some(substr) = arg.get(1..) {
-                for abbr in substr.chars() {
-                    vec_push_hook(&mut ret_args, &abbr.to_string(), &mut expect_pattern)?;
-                }
-            }
-        } else if expect_pattern {
+    for (position, arg) in args.iter().enumerate() {
+        if expect_pattern {
+            // taken as is, so a pattern may also start with '-'
             ret_args.push(arg.to_string());
             expect_pattern = false;
+        } else if arg == "--" {
+            // no options after it, only the directory if not given yet
+            match (&args[position + 1..], &directory) {
+                ([], _) => {}
+                ([path], Option::None) => directory = Some(path.to_string()),
+                _ => return Err(()),
+            }
+            break;
+        } else if let Some(substr) = arg.strip_prefix("--") {
+            match substr.split_once('=') {
+                Some((flag, para)) if need_extra_para(flag) => {
+                    ret_args.push(flag.to_string());
+                    ret_args.push(para.to_string());
+                }
+                Some(_) => return Err(()),
+                Option::None => {
+                    expect_pattern = need_extra_para(substr);
+                    ret_args.push(substr.to_string());
+                }
+            }
+        } else if let Some(substr) = arg.strip_prefix('-') {
+            for (index, abbr) in substr.char_indices() {
+                ret_args.push(abbr.to_string());
+                if need_extra_para(&abbr.to_string()) {
+                    // the rest of this arg, or the next one
+                    let rest = &substr[index + abbr.len_utf8()..];
+                    if rest.is_empty() {
+                        expect_pattern = true;
+                    } else {
+                        ret_args.push(rest.to_string());
+                    }
+                    break;
+                }
+            }
+        } else if directory.is_none() {
+            directory = Some(arg.to_string());
         } else {
             return Err(());
         }
     }
-    Ok(ret_args)
-}
 
-fn parse_parameter() -> Setting {
-    let mut args: Vec<String> = env::args().collect();
-    // remove the first arg which is command name
-    args.remove(0);
-    //extract last parameter, if not a path, put it back
-    let root_path: PathBuf = if let Some(path) = args.pop() {
-        if PathBuf::from(path.clone()).is_dir() {
-            PathBuf::from(path.clone())
-        } else {
-            args.push(path);
-            PathBuf::from("./".to_string())
-        }
-    } else {
-        PathBuf::from("./".to_string())
+    if expect_pattern {
+        return Err(());
     }
-    .canonicalize()
-    .unwrap();
-    let mut ret = Setting {
-        root: root_path,
-        ..Default::default()
-    };
-
-    let wtf = match decompose_arg(&args) {
-        Ok(vec) => vec,
-        Err(_) => {
-            Setting::print_help();
-            exit(0);
-        }
-    };
+    Ok((ret_args, directory))
+}
+
+/*
+    @brief
+        build options from command line arguments, the command name
+        excluded
+*/
+fn parse_parameter(args: Vec<String>) -> Result<Options, ArgsError> {
+    let (wtf, directory) = decompose_arg(&args).map_err(|_| {
+        ArgsError::Invalid("Invalid or incomplete arguments: ".to_string() + &args.join(" "))
+    })?;
+    let root_path = PathBuf::from(directory.unwrap_or_else(|| "./".to_string()));
+    let mut ret = Options::new(root_path);
+    ret.colors = ColorTable::from_env();
+
     let mut args_iter = wtf.iter().peekable();
 
-    while args_iter.peek() != Option::None {
-        let i: &String = args_iter.next().unwrap();
+    while let Some(i) = args_iter.next() {
         match i.as_ref() {
             "a" => ret.is_all = true,
             "d" => ret.is_dir_only = true,
+            "l" => ret.is_following_links = true,
+            "x" => ret.is_one_filesystem = true,
             "i" => ret.is_no_indentation = true,
-            "v" => ret.is_sort_alphanumerically = true,
-            "t" => ret.is_sort_mod_time = true,
-            "U" => ret.is_unsort = true,
+            "v" => ret.sort_rules = Options::single_sort_rule(SortKey::Version),
+            "t" => ret.sort_rules = Options::single_sort_rule(SortKey::ModifiedTime),
+            // -D shows the time it sorts by
+            "c" => {
+                ret.sort_rules = Options::single_sort_rule(SortKey::ChangeTime);
+                ret.time_field = TimeField::Changed;
+            }
+            "U" => ret.sort_rules = Vec::new(),
+            "sort" => {
+                let list: &str = args_iter.next().expect("need a sort name here");
+                match parse_sort_rules(list) {
+                    Some(rules) => ret.sort_rules = rules,
+                    Option::None => {
+                        return Err(ArgsError::Invalid("Invalid sort key: ".to_string() + list))
+                    }
+                }
+            }
             "r" => ret.is_sort_reverse = true,
             "dirsfirst" => ret.is_dir_first = true,
             "noreport" => ret.is_needing_report = false,
             "ignore-case" => ret.pattern_ignore_case = true,
+            "matchdirs" => ret.is_match_dirs = true,
+            "prune" => ret.is_prune = true,
+            "gitignore" => ret.use_gitignore = true,
+            "show-ignored" => ret.is_showing_ignored = true,
             "Q" => ret.is_quote = true,
+            // -q, -N will override each other
+            "q" => ret.name_format = NameFormat::Question,
+            "N" => ret.name_format = NameFormat::Raw,
             "f" => ret.is_full_path = true,
+            "F" => ret.is_classify = true,
             // -n will be overwrite, no reaction
             "C" => ret.is_color = true,
             "p" => ret.need_protection = true,
             "u" => ret.need_uid = true,
             "g" => ret.need_gid = true,
-            "D" => ret.need_ctime = true,
+            "numeric-ids" => ret.is_numeric_ids = true,
+            "D" => ret.need_time = true,
+            "time" => {
+                let field: &str = args_iter.next().expect("need a time name here");
+                match field {
+                    "atime" => ret.time_field = TimeField::Accessed,
+                    "mtime" => ret.time_field = TimeField::Modified,
+                    "ctime" => ret.time_field = TimeField::Changed,
+                    "birth" => ret.time_field = TimeField::Birth,
+                    _ => {
+                        return Err(ArgsError::Invalid(
+                            "Invalid time name: ".to_string() + field,
+                        ))
+                    }
+                }
+            }
+            "timefmt" => {
+                let format: &str = args_iter.next().expect("need a format here");
+                ret.time_format = Some(format.to_string());
+                ret.need_time = true;
+            }
             "inodes" => ret.need_inode = true,
             "device" => ret.need_device = true,
             // -s, -h, --si will override others
-            "s" => ret.need_size = 1,
-            "h" => ret.need_size = 2,
-            "si" => ret.need_size = 3,
+            "s" => ret.need_size = Some(SizeFormat::Bytes),
+            "h" => ret.need_size = Some(SizeFormat::Binary),
+            "si" => ret.need_size = Some(SizeFormat::Si),
+            "J" => ret.output_mode = OutputMode::Json,
+            "X" => ret.output_mode = OutputMode::Xml,
+            "nolinks" => ret.is_no_links = true,
+            "H" => {
+                ret.output_mode = OutputMode::Html;
+                let base_href: &str = args_iter.next().expect("need a baseHREF here");
+                ret.base_href = base_href.to_string();
+            }
+            "T" => {
+                let title: &str = args_iter.next().expect("need a title here");
+                ret.html_title = title.to_string();
+            }
+            // both can be given many times
             "P" => {
-                ret.pattern_p = true;
-                // only can exist one pattern
-                if ret.pattern_p && ret.pattern_i {
-                    ret.pattern_i = false;
-                }
                 let pattern: &str = args_iter.next().expect("need a pattern here");
-                ret.pattern = pattern.to_string();
+                ret.include_patterns.push(pattern.to_string());
             }
             "I" => {
-                ret.pattern_i = true;
-                // only can exist one pattern
-                if ret.pattern_p && ret.pattern_i {
-                    ret.pattern_p = false;
-                }
                 let pattern: &str = args_iter.next().expect("need a pattern here");
-                ret.pattern = pattern.to_string();
+                ret.exclude_patterns.push(pattern.to_string());
             }
-            "L" => {
-                ret.is_level_limited = true;
+            "L" | "level" => {
                 let level: &str = args_iter.next().expect("need a number here");
-                ret.level = level.to_string();
+                match level.parse::<usize>() {
+                    Ok(level) if level > 0 => ret.max_level = Some(level),
+                    _ => {
+                        return Err(ArgsError::Invalid(
+                            "Invalid level, must be greater than 0: ".to_string() + level,
+                        ))
+                    }
+                }
+            }
+            "R" => ret.is_rerun = true,
+            "o" => {
+                let file: &str = args_iter.next().expect("need a filename here");
+                ret.output_file = Some(PathBuf::from(file));
             }
             "filelimit" => {
-                ret.is_file_number_limited = true;
                 let max_files: &str = args_iter.next().expect("need a number here");
-                ret.max_files = max_files.to_string();
-            }
-
-            "help" => {
-                Setting::print_help();
-                exit(0);
+                match max_files.parse::<usize>() {
+                    Ok(max_files) => ret.file_limit = Some(max_files),
+                    _ => {
+                        return Err(ArgsError::Invalid(
+                            "Invalid number after --filelimit: ".to_string() + max_files,
+                        ))
+                    }
+                }
             }
-            "version" => {
-                println!("wtree, version 0.3.0 by @waynexia");
-                exit(0);
+            "threads" => {
+                let threads: &str = args_iter.next().expect("need a number here");
+                match threads.parse::<usize>() {
+                    Ok(threads) if threads > 0 => ret.threads = threads,
+                    _ => {
+                        return Err(ArgsError::Invalid(
+                            "Invalid number of threads, must be greater than 0: ".to_string()
+                                + threads,
+                        ))
+                    }
+                }
             }
 
-            _ => Setting::error_report("Invalid argument: ".to_string() + i.as_ref()),
+            "help" => return Err(ArgsError::Help),
+            "version" => return Err(ArgsError::Version),
+
+            _ => {
+                return Err(ArgsError::Invalid(
+                    "Invalid argument: ".to_string() + i.as_ref(),
+                ))
+            }
         }
     }
 
-    ret
+    Ok(ret)
 }
 
 #[test]
 fn test_fn_decompose_arg() {
-    let args = decompose_arg(&vec![String::from("-vga"), String::from("--inodes")]).unwrap();
+    let (args, directory) =
+        decompose_arg(&[String::from("-vga"), String::from("--inodes")]).unwrap();
     assert_eq!(
         args,
         vec![
@@ -372,11 +787,87 @@ fn test_fn_decompose_arg() {
             String::from("a"),
             String::from("inodes")
         ]
-    )
+    );
+    assert_eq!(directory, Option::None);
 }
 
 #[test]
 #[should_panic]
 fn decompose_arg_with_incorrect_input() {
-    let _args = decompose_arg(&vec![String::from("-Io")]).unwrap();
+    let _args = decompose_arg(&[String::from("-aI")]).unwrap();
+}
+
+#[test]
+fn test_fn_decompose_arg_with_parameter() {
+    let (args, directory) = decompose_arg(&[
+        String::from("-aL2"),
+        String::from("src"),
+        String::from("--level=3"),
+        String::from("-I"),
+        String::from("-x"),
+        String::from("--sort"),
+        String::from("name"),
+        String::from("--"),
+    ])
+    .unwrap();
+    assert_eq!(
+        args,
+        vec!["a", "L", "2", "level", "3", "I", "-x", "sort", "name"]
+    );
+    assert_eq!(directory.as_deref(), Some("src"));
+    // nothing after `--` is an option
+    let (args, directory) = decompose_arg(&[
+        String::from("-a"),
+        String::from("--"),
+        String::from("--sort"),
+    ])
+    .unwrap();
+    assert_eq!(args, vec!["a"]);
+    assert_eq!(directory.as_deref(), Some("--sort"));
+    // only one directory
+    assert!(decompose_arg(&[String::from("a"), String::from("b")]).is_err());
+    assert!(decompose_arg(&[String::from("a"), String::from("--"), String::from("b")]).is_err());
+    assert_eq!(
+        decompose_arg(&[String::from("--filelimit=100")]).unwrap().0,
+        vec!["filelimit", "100"]
+    );
+    assert!(decompose_arg(&[String::from("--inodes=1")]).is_err());
+}
+
+#[test]
+fn test_fn_from_args() {
+    let args = |list: &[&str]| list.iter().map(|arg| arg.to_string()).collect();
+    assert_eq!(
+        Options::from_args(args(&["--help"])).err(),
+        Some(ArgsError::Help)
+    );
+    assert_eq!(
+        Options::from_args(args(&["--version"])).err(),
+        Some(ArgsError::Version)
+    );
+    assert_eq!(
+        Options::from_args(args(&["-aI"])).err(),
+        Some(ArgsError::Invalid(String::from(
+            "Invalid or incomplete arguments: -aI"
+        )))
+    );
+    assert_eq!(
+        Options::from_args(args(&["-L", "0"])).err(),
+        Some(ArgsError::Invalid(String::from(
+            "Invalid level, must be greater than 0: 0"
+        )))
+    );
+
+    let options = Options::from_args(args(&["-a", "-L", "2", "."])).unwrap();
+    assert_eq!(options.max_level, Some(2));
+    assert_eq!(options.get_root(), std::env::current_dir().unwrap());
+
+    // parameters are taken first, even when they name a directory
+    let options = Options::from_args(args(&["-I", "src", "-P", "src"])).unwrap();
+    assert_eq!(options.exclude_patterns, vec!["src"]);
+    assert_eq!(options.include_patterns, vec!["src"]);
+    assert_eq!(options.get_root(), std::env::current_dir().unwrap());
+    let options = Options::from_args(args(&["-x", "/", "--noreport"])).unwrap();
+    assert_eq!(options.get_root(), PathBuf::from("/"));
+    assert!(options.is_one_filesystem && !options.is_needing_report);
 }
@@ -0,0 +1,253 @@
+use crate::pattern::path_match;
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/*
+    one line of a .gitignore file
+*/
+#[derive(Debug)]
+struct Rule {
+    pattern: String,
+    is_negated: bool,  // leading `!`
+    is_dir_only: bool, // trailing `/`
+    is_anchored: bool, // contains `/`, match against the path instead of the name
+}
+
+impl Rule {
+    fn parse(line: &str) -> Option<Rule> {
+        let line = trim_trailing_space(line);
+        if line.is_empty() || line.starts_with('#') {
+            return Option::None;
+        }
+
+        let (is_negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            Option::None => (false, line),
+        };
+        // `\#` and `\!` are literals
+        let line = if line.starts_with("\\#") || line.starts_with("\\!") {
+            &line[1..]
+        } else {
+            line
+        };
+        let (is_dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            Option::None => (false, line),
+        };
+        if line.is_empty() {
+            return Option::None;
+        }
+        let is_anchored = line.contains('/');
+
+        Some(Rule {
+            pattern: line.trim_start_matches('/').to_string(),
+            is_negated,
+            is_dir_only,
+            is_anchored,
+        })
+    }
+
+    // `relative` is the path from the directory holding the rule
+    fn is_match(&self, relative: &str, is_dir: bool) -> bool {
+        if self.is_dir_only && !is_dir {
+            return false;
+        }
+        if self.is_anchored {
+            path_match(&self.pattern, relative)
+        } else {
+            let name = relative.rsplit('/').next().unwrap_or(relative);
+            path_match(&self.pattern, name)
+        }
+    }
+}
+
+/*
+    trailing spaces are ignored unless they are quoted with backslash
+*/
+fn trim_trailing_space(line: &str) -> &str {
+    let mut end = line.len();
+    while line[..end].ends_with(' ') && !line[..end - 1].ends_with('\\') {
+        end -= 1;
+    }
+    &line[..end]
+}
+
+/*
+    rules of one directory level, chained to the rules of the parent
+    directories. Later rules and deeper levels take precedence.
+*/
+#[derive(Debug)]
+pub struct IgnoreRules {
+    base: PathBuf,
+    rules: Vec<Rule>,
+    parent: Option<Arc<IgnoreRules>>,
+}
+
+impl IgnoreRules {
+    /*
+        @brief
+            collect the global excludes file, `.git/info/exclude` and
+            every .gitignore from the top of the repository down to the
+            parent of `root`. The one in `root` is read when it is opened.
+    */
+    pub fn for_root(root: &Path) -> Arc<IgnoreRules> {
+        let top = find_repository(root).unwrap_or_else(|| root.to_path_buf());
+
+        let mut rules = Vec::new();
+        if let Some(global) = global_excludes_file() {
+            rules.extend(read_rules(&global));
+        }
+        rules.extend(read_rules(&top.join(".git/info/exclude")));
+        let mut current = Arc::new(IgnoreRules {
+            base: top.clone(),
+            rules,
+            parent: Option::None,
+        });
+
+        let mut dir = top.clone();
+        if let Ok(relative) = root.strip_prefix(&top) {
+            for component in relative.components() {
+                current = IgnoreRules::descend(&current, &dir);
+                dir.push(component);
+            }
+        }
+
+        current
+    }
+
+    /*
+        @brief
+            rules for the children of `dir`, adding its .gitignore if any
+    */
+    pub fn descend(parent: &Arc<IgnoreRules>, dir: &Path) -> Arc<IgnoreRules> {
+        let rules = read_rules(&dir.join(".gitignore"));
+        if rules.is_empty() {
+            return parent.clone();
+        }
+        Arc::new(IgnoreRules {
+            base: dir.to_path_buf(),
+            rules,
+            parent: Some(parent.clone()),
+        })
+    }
+
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        // git never shows its own directory
+        if is_dir && path.file_name() == Some(OsStr::new(".git")) {
+            return true;
+        }
+
+        let mut level = Some(self);
+        while let Some(rules) = level {
+            if let Some(relative) = path.strip_prefix(&rules.base).ok().and_then(Path::to_str) {
+                if let Some(rule) = rules
+                    .rules
+                    .iter()
+                    .rev()
+                    .find(|rule| rule.is_match(relative, is_dir))
+                {
+                    return !rule.is_negated;
+                }
+            }
+            level = rules.parent.as_deref();
+        }
+
+        false
+    }
+}
+
+fn read_rules(path: &Path) -> Vec<Rule> {
+    match fs::read_to_string(path) {
+        Ok(content) => content.lines().filter_map(Rule::parse).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn find_repository(root: &Path) -> Option<PathBuf> {
+    root.ancestors()
+        .find(|dir| dir.join(".git").exists())
+        .map(Path::to_path_buf)
+}
+
+/*
+    @brief
+        `core.excludesFile` from the user's git config, or the
+        default `$XDG_CONFIG_HOME/git/ignore`
+*/
+fn global_excludes_file() -> Option<PathBuf> {
+    let home = env::var_os("HOME").map(PathBuf::from);
+    let config_home = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => home.clone()?.join(".config"),
+    };
+
+    let mut configs = vec![config_home.join("git/config")];
+    if let Some(home) = &home {
+        configs.push(home.join(".gitconfig"));
+    }
+    // the later file wins, same as git
+    let configured = configs
+        .iter()
+        .rev()
+        .find_map(|config| read_excludes_file_option(config));
+
+    match configured {
+        Some(file) => match (file.strip_prefix("~/"), &home) {
+            (Some(rest), Some(home)) => Some(home.join(rest)),
+            _ => Some(PathBuf::from(file)),
+        },
+        Option::None => Some(config_home.join("git/ignore")),
+    }
+}
+
+fn read_excludes_file_option(config: &Path) -> Option<String> {
+    let content = fs::read_to_string(config).ok()?;
+    let mut in_core = false;
+    let mut value = Option::None;
+    for line in content.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_core = line.to_lowercase() == "[core]";
+        } else if in_core {
+            if let Some((key, val)) = line.split_once('=') {
+                if key.trim().eq_ignore_ascii_case("excludesfile") {
+                    value = Some(val.trim().trim_matches('"').to_string());
+                }
+            }
+        }
+    }
+    value
+}
+
+#[test]
+fn test_fn_rule_parse() {
+    let rule = Rule::parse("!/build/ ").unwrap();
+    assert_eq!(rule.pattern, "build");
+    assert!(rule.is_negated && rule.is_dir_only && rule.is_anchored);
+    assert!(Rule::parse("# comment").is_none());
+    assert!(Rule::parse("   ").is_none());
+    assert_eq!(Rule::parse("\\#hash").unwrap().pattern, "#hash");
+    assert_eq!(Rule::parse("space\\ ").unwrap().pattern, "space\\ ");
+}
+
+#[test]
+fn test_fn_ignore_rules() {
+    let rules = IgnoreRules {
+        base: PathBuf::from("/repo"),
+        rules: ["*.log", "!keep.log", "/target", "build/", "docs/**/*.tmp"]
+            .iter()
+            .filter_map(|line| Rule::parse(line))
+            .collect(),
+        parent: Option::None,
+    };
+    assert!(rules.is_ignored(Path::new("/repo/a/debug.log"), false));
+    assert!(!rules.is_ignored(Path::new("/repo/a/keep.log"), false));
+    assert!(rules.is_ignored(Path::new("/repo/target"), true));
+    assert!(!rules.is_ignored(Path::new("/repo/src/target"), true));
+    assert!(rules.is_ignored(Path::new("/repo/src/build"), true));
+    assert!(!rules.is_ignored(Path::new("/repo/src/build"), false));
+    assert!(rules.is_ignored(Path::new("/repo/docs/x/y.tmp"), false));
+    assert!(rules.is_ignored(Path::new("/repo/.git"), true));
+}
@@ -0,0 +1,23 @@
+/*
+    wtree as a library: build `Options`, walk with `Walker` to get
+    events or whole trees, and print them with `Renderer`.
+*/
+mod colors;
+mod envir;
+mod gitignore;
+mod parallel;
+mod pattern;
+mod print;
+mod sort;
+mod timefmt;
+mod users;
+mod utils;
+mod wtree;
+
+pub use crate::envir::{
+    ArgsError, Context, NameFormat, Options, OutputMode, SizeFormat, TimeField,
+};
+pub use crate::print::Renderer;
+pub use crate::sort::{SortKey, SortRule};
+pub use crate::utils::Entry;
+pub use crate::wtree::{print_tree, write_tree, Counter, Event, Node, Walker};
@@ -1,9 +1,33 @@
-mod envir;
-mod print;
-mod utils;
-mod wtree;
-fn main() -> std::io::Result<()> {
-    wtree::print_tree()?;
+use std::env;
+use std::io::ErrorKind;
+use std::process::exit;
+use wtree::{ArgsError, Options};
 
-    Ok(())
+fn main() -> std::io::Result<()> {
+    // skip the command name
+    let options = match Options::from_args(env::args().skip(1).collect()) {
+        Ok(options) => options,
+        Err(ArgsError::Help) => {
+            Options::print_help();
+            return Ok(());
+        }
+        Err(ArgsError::Version) => {
+            Options::print_version();
+            return Ok(());
+        }
+        // keep stdout for the tree, scripts see the failure in the exit code
+        Err(ArgsError::Invalid(hint)) => {
+            eprintln!("wtree: {}", hint);
+            eprintln!("Try 'wtree --help' for more information.");
+            exit(1);
+        }
+    };
+    match wtree::print_tree(options) {
+        Ok(0) => Ok(()),
+        // everything readable was printed
+        Ok(_) => exit(1),
+        // the reader went away, as in `wtree | head`
+        Err(err) if err.kind() == ErrorKind::BrokenPipe => Ok(()),
+        Err(err) => Err(err),
+    }
 }
@@ -0,0 +1,207 @@
+use crate::envir::Context;
+use crate::utils::Entry;
+use crate::wtree::{is_beyond_level, read_listing, Listing};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+
+/*
+    a directory waiting to be read. `order` is the position of each
+    ancestor among its siblings, so comparing them gives the order the
+    tree is printed in.
+*/
+struct Job {
+    order: Vec<usize>,
+    dir: Entry,
+    depth: usize, // depth of its entries
+}
+
+// BinaryHeap pops the greatest, make it the one printed first
+impl Ord for Job {
+    fn cmp(&self, other: &Job) -> Ordering {
+        other.order.cmp(&self.order)
+    }
+}
+
+impl PartialOrd for Job {
+    fn partial_cmp(&self, other: &Job) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Job {
+    fn eq(&self, other: &Job) -> bool {
+        self.order == other.order
+    }
+}
+
+impl Eq for Job {}
+
+// listings a worker may read ahead of the printer, before waiting for it
+const READ_AHEAD_PER_THREAD: usize = 16;
+
+struct State {
+    jobs: BinaryHeap<Job>,
+    scheduled: HashSet<PathBuf>, // queued, being read or read but not taken
+    listings: HashMap<PathBuf, Listing>,
+    reading: usize, // jobs taken by workers, not in `listings` yet
+    is_finished: bool,
+}
+
+/*
+    reads directories ahead of the printer on worker threads. Workers
+    always pick the pending directory that will be printed first, and
+    queue the subdirectories of every listing they read. The printer
+    takes listings with `read` in its usual order, so the output is
+    the same as reading serially. Workers stop reading ahead once a
+    few listings per thread wait for the printer.
+*/
+pub struct Pool {
+    context: Arc<Context>,
+    state: Mutex<State>,
+    job_ready: Condvar,
+    listing_ready: Condvar,
+}
+
+impl Pool {
+    pub fn new(context: Arc<Context>) -> Pool {
+        Pool {
+            context,
+            state: Mutex::new(State {
+                jobs: BinaryHeap::new(),
+                scheduled: HashSet::new(),
+                listings: HashMap::new(),
+                reading: 0,
+                is_finished: false,
+            }),
+            job_ready: Condvar::new(),
+            listing_ready: Condvar::new(),
+        }
+    }
+
+    pub fn start(&self, root: &Entry) {
+        if root.can_descend(&self.context) {
+            let mut state = self.state.lock().unwrap();
+            Pool::schedule(&mut state, Vec::new(), root.clone(), 1);
+            self.job_ready.notify_one();
+        }
+    }
+
+    // let the workers return, pending jobs are dropped
+    pub fn finish(&self) {
+        self.state.lock().unwrap().is_finished = true;
+        self.job_ready.notify_all();
+    }
+
+    /*
+        @brief
+            the listing of `dir`, waiting for a worker if it is being
+            read. Directories still queued or never queued are read on
+            this thread, workers may be waiting for the printer.
+    */
+    pub fn read(&self, dir: &Entry, depth: usize) -> Listing {
+        let path = dir.get_path();
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(listing) = state.listings.remove(path) {
+                state.scheduled.remove(path);
+                self.job_ready.notify_one();
+                return listing;
+            }
+            if let Some(job) = Pool::take_job(&mut state, path) {
+                state.scheduled.remove(path);
+                drop(state);
+                return self.read_job(&job);
+            }
+            if !state.scheduled.contains(path) {
+                drop(state);
+                let mut pruned = HashMap::new();
+                let listing = read_listing(&self.context, dir, depth, &mut pruned);
+                Pool::keep_pruned(&mut self.state.lock().unwrap(), pruned);
+                return listing;
+            }
+            state = self.listing_ready.wait(state).unwrap();
+        }
+    }
+
+    pub fn work(&self) {
+        while let Some(job) = self.next_job() {
+            let listing = self.read_job(&job);
+
+            let mut state = self.state.lock().unwrap();
+            state.reading -= 1;
+            state.listings.insert(job.dir.get_path().clone(), listing);
+            self.listing_ready.notify_all();
+        }
+    }
+
+    // read the dir of a job and queue its subdirectories
+    fn read_job(&self, job: &Job) -> Listing {
+        let mut pruned = HashMap::new();
+        let listing = read_listing(&self.context, &job.dir, job.depth, &mut pruned);
+
+        let mut state = self.state.lock().unwrap();
+        Pool::keep_pruned(&mut state, pruned);
+        if let Listing::Entries(path_list) = &listing {
+            // same conditions as `open_dir` of the printer
+            if !is_beyond_level(&self.context.options, job.depth + 1) {
+                for (index, child) in path_list.iter().enumerate() {
+                    // dirs read by --prune are scheduled already
+                    if child.can_descend(&self.context)
+                        && !state.scheduled.contains(child.get_path())
+                    {
+                        let mut order = job.order.clone();
+                        order.push(index);
+                        Pool::schedule(&mut state, order, child.clone(), job.depth + 1);
+                        self.job_ready.notify_one();
+                    }
+                }
+            }
+        }
+        listing
+    }
+
+    fn next_job(&self) -> Option<Job> {
+        let read_ahead = self.context.options.threads * READ_AHEAD_PER_THREAD;
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.is_finished {
+                return Option::None;
+            }
+            if state.listings.len() + state.reading < read_ahead {
+                if let Some(job) = state.jobs.pop() {
+                    state.reading += 1;
+                    return Some(job);
+                }
+            }
+            state = self.job_ready.wait(state).unwrap();
+        }
+    }
+
+    // the job of `path` if no worker has taken it yet
+    fn take_job(state: &mut State, path: &Path) -> Option<Job> {
+        // `into_vec` keeps the order of `iter`
+        let index = state
+            .jobs
+            .iter()
+            .position(|job| job.dir.get_path() == path)?;
+        let mut jobs = std::mem::take(&mut state.jobs).into_vec();
+        let job = jobs.swap_remove(index);
+        state.jobs = BinaryHeap::from(jobs);
+        Some(job)
+    }
+
+    fn schedule(state: &mut State, order: Vec<usize>, dir: Entry, depth: usize) {
+        state.scheduled.insert(dir.get_path().clone());
+        state.jobs.push(Job { order, dir, depth });
+    }
+
+    // listings --prune read along with another one, for `read` to take
+    fn keep_pruned(state: &mut State, pruned: HashMap<PathBuf, Listing>) {
+        for (path, listing) in pruned {
+            state.scheduled.insert(path.clone());
+            state.listings.insert(path, listing);
+        }
+    }
+}
@@ -0,0 +1,258 @@
+/*
+    wildcard matching used by -P and -I, same syntax as tree:
+        *       any number of characters
+        ?       exactly one character
+        [...]   one character of the set, `a-z` ranges and leading `^` or `!` to negate
+        \x      literal x
+        a|b     either pattern a or pattern b
+*/
+
+use std::collections::HashSet;
+
+// inclusive character ranges of a bracket expression
+type CharSet = Vec<(char, char)>;
+
+pub fn pattern_match(pattern: &str, name: &str, ignore_case: bool) -> bool {
+    let name: Vec<char> = prepare(name, ignore_case);
+    split_alternatives(pattern)
+        .iter()
+        .any(|alternative| match_from(&prepare(alternative, ignore_case), &name, false))
+}
+
+pub fn match_any(patterns: &[String], name: &str, ignore_case: bool) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| pattern_match(pattern, name, ignore_case))
+}
+
+fn prepare(raw: &str, ignore_case: bool) -> Vec<char> {
+    if ignore_case {
+        raw.to_lowercase().chars().collect()
+    } else {
+        raw.chars().collect()
+    }
+}
+
+/*
+    @brief
+        split on `|`, except when it is escaped or inside brackets
+*/
+fn split_alternatives(pattern: &str) -> Vec<String> {
+    let mut alternatives = Vec::new();
+    let mut current = String::new();
+    let mut in_bracket = false;
+    let mut chars = pattern.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                current.push(c);
+                if let Some(escaped) = chars.next() {
+                    current.push(escaped);
+                }
+            }
+            '[' => {
+                in_bracket = true;
+                current.push(c);
+            }
+            ']' => {
+                in_bracket = false;
+                current.push(c);
+            }
+            '|' if !in_bracket => alternatives.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+    alternatives.push(current);
+
+    alternatives
+}
+
+/*
+    @brief
+        match a whole path, as .gitignore does: `*`, `?` and `[...]`
+        stop at `/`, while `**` crosses directories.
+*/
+pub fn path_match(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let path: Vec<char> = path.chars().collect();
+    match_from(&pattern, &path, true)
+}
+
+fn match_from(pattern: &[char], name: &[char], is_path: bool) -> bool {
+    Matcher {
+        is_path,
+        failed: HashSet::new(),
+    }
+    .matches(pattern, name)
+}
+
+/*
+    stars make the same rest of a pattern be tried against the same
+    rest of a name again and again, remembering the failures keeps
+    matching polynomial. Suffixes are known by their lengths.
+*/
+struct Matcher {
+    is_path: bool,
+    failed: HashSet<(usize, usize)>,
+}
+
+impl Matcher {
+    fn matches(&mut self, pattern: &[char], name: &[char]) -> bool {
+        let key = (pattern.len(), name.len());
+        if self.failed.contains(&key) {
+            return false;
+        }
+        let is_matched = self.match_here(pattern, name);
+        if !is_matched {
+            self.failed.insert(key);
+        }
+        is_matched
+    }
+
+    fn match_here(&mut self, pattern: &[char], name: &[char]) -> bool {
+        let is_path = self.is_path;
+        // in path mode nothing but `**` can match a `/`
+        let is_single = move |c: &char| !(is_path && *c == '/');
+        match pattern.first() {
+            Option::None => name.is_empty(),
+            Some('*') if is_path && pattern.get(1) == Some(&'*') => {
+                if pattern.get(2) == Some(&'/') {
+                    // `**/` matches zero or more whole directories
+                    let rest = &pattern[3..];
+                    self.matches(rest, name)
+                        || (0..name.len()).any(|index| {
+                            name[index] == '/' && self.matches(rest, &name[index + 1..])
+                        })
+                } else {
+                    (0..=name.len()).any(|skip| self.matches(&pattern[2..], &name[skip..]))
+                }
+            }
+            Some('*') => {
+                // try every possible length for this star
+                let max_skip = name
+                    .iter()
+                    .position(|c| !is_single(c))
+                    .unwrap_or(name.len());
+                (0..=max_skip).any(|skip| self.matches(&pattern[1..], &name[skip..]))
+            }
+            Some('?') => match name.first() {
+                Some(c) => is_single(c) && self.matches(&pattern[1..], &name[1..]),
+                Option::None => false,
+            },
+            Some('[') => match (name.first(), match_bracket(&pattern[1..])) {
+                (Some(c), Some((set, negated, used))) => {
+                    is_single(c)
+                        && (set_contains(&set, *c) ^ negated)
+                        && self.matches(&pattern[used + 1..], &name[1..])
+                }
+                // unclosed bracket, take `[` literally
+                (Some(c), Option::None) => *c == '[' && self.matches(&pattern[1..], &name[1..]),
+                (Option::None, _) => false,
+            },
+            Some('\\') if pattern.len() > 1 => {
+                name.first() == Some(&pattern[1]) && self.matches(&pattern[2..], &name[1..])
+            }
+            Some(c) => name.first() == Some(c) && self.matches(&pattern[1..], &name[1..]),
+        }
+    }
+}
+
+/*
+    @brief
+        parse a bracket expression, `pattern` starts right after `[`.
+    @return
+        (char set, is negated, number of chars consumed including `]`)
+*/
+fn match_bracket(pattern: &[char]) -> Option<(CharSet, bool, usize)> {
+    let mut index = 0;
+    let negated = matches!(pattern.first(), Some('^') | Some('!'));
+    if negated {
+        index += 1;
+    }
+
+    let mut set = Vec::new();
+    let mut is_first = true;
+    while index < pattern.len() {
+        let mut c = pattern[index];
+        // a `]` right after `[` or `[^` is a literal
+        if c == ']' && !is_first {
+            return Some((set, negated, index + 1));
+        }
+        is_first = false;
+        if c == '\\' && index + 1 < pattern.len() {
+            index += 1;
+            c = pattern[index];
+        }
+        if index + 2 < pattern.len() && pattern[index + 1] == '-' && pattern[index + 2] != ']' {
+            set.push((c, pattern[index + 2]));
+            index += 3;
+        } else {
+            set.push((c, c));
+            index += 1;
+        }
+    }
+
+    Option::None
+}
+
+fn set_contains(set: &[(char, char)], c: char) -> bool {
+    set.iter().any(|(low, high)| *low <= c && c <= *high)
+}
+
+#[test]
+fn test_fn_pattern_match_wildcards() {
+    assert!(pattern_match("*.rs", "main.rs", false));
+    assert!(!pattern_match("*.rs", "main.rs.bak", false));
+    assert!(pattern_match("ma?n.*", "main.rs", false));
+    assert!(!pattern_match("?", "", false));
+    assert!(pattern_match("*", "", false));
+    assert!(pattern_match("a\\*", "a*", false));
+    assert!(!pattern_match("a\\*", "ab", false));
+}
+
+#[test]
+fn test_fn_pattern_match_many_stars() {
+    let name = "a".repeat(40);
+    assert!(!pattern_match("*a*a*a*a*a*a*a*b", &name, false));
+    assert!(pattern_match("*a*a*a*a*a*a*a*", &name, false));
+    assert!(!path_match("**/*a*a*a*a*a*a*b", &name));
+}
+
+#[test]
+fn test_fn_pattern_match_brackets() {
+    assert!(pattern_match("[a-c]x", "bx", false));
+    assert!(!pattern_match("[a-c]x", "dx", false));
+    assert!(pattern_match("[^a-c]x", "dx", false));
+    assert!(pattern_match("[!a-c]x", "dx", false));
+    assert!(pattern_match("[]]", "]", false));
+    assert!(pattern_match("[a|b]", "|", false));
+}
+
+#[test]
+fn test_fn_pattern_match_alternatives_and_case() {
+    assert!(pattern_match("*.rs|*.toml", "Cargo.toml", false));
+    assert!(!pattern_match("*.rs|*.toml", "README.md", false));
+    assert!(pattern_match("readme*", "README.md", true));
+    assert!(!pattern_match("readme*", "README.md", false));
+}
+
+#[test]
+fn test_fn_match_any() {
+    let patterns = vec![String::from("target"), String::from(".git")];
+    assert!(match_any(&patterns, ".git", false));
+    assert!(!match_any(&patterns, "src", false));
+    assert!(!match_any(&[], "src", false));
+}
+
+#[test]
+fn test_fn_path_match() {
+    assert!(path_match("src/*.rs", "src/main.rs"));
+    assert!(!path_match("src/*.rs", "src/bin/main.rs"));
+    assert!(path_match("**/target", "target"));
+    assert!(path_match("**/target", "a/b/target"));
+    assert!(path_match("doc/**", "doc/a/b.md"));
+    assert!(path_match("a/**/b", "a/b"));
+    assert!(path_match("a/**/b", "a/x/y/b"));
+    assert!(!path_match("a?b", "a/b"));
+}
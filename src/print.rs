@@ -1,75 +1,666 @@
-use crate::envir::SETTING;
-use crate::utils::{Entry, EntryAttr, Prefix};
-use std::fs;
-use std::os::unix::fs::PermissionsExt;
+use crate::envir::{Options, OutputMode};
+use crate::utils::{AttrValue, Entry, EntryAttr, Prefix};
+use crate::wtree::{Counter, Event};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
 
-/* todo: use enum */
-fn set_color(color: u8) {
-    let esc_char = vec![27];
-    let esc = String::from_utf8(esc_char).unwrap();
-    let bright: u8 = 1;
-    print!("{}[{};{}m", esc, bright, color);
+fn set_color(out: &mut dyn Write, code: &str) -> io::Result<()> {
+    write!(out, "\x1b[{}m", code)
 }
 
-fn set_black() {
-    let black: u8 = 30;
-    set_color(black);
+fn reset(out: &mut dyn Write) -> io::Result<()> {
+    set_color(out, "0")
 }
-fn set_red() {
-    let red: u8 = 31;
-    set_color(red);
+
+// element name in structured outputs
+fn get_type_name(entry: &Entry) -> &'static str {
+    match entry.get_file_type() {
+        Some(file_type) => file_type.get_name(),
+        Option::None => "file",
+    }
 }
 
-fn set_green() {
-    let green: u8 = 32;
-    set_color(green);
+// only the text outputs need their columns lined up
+fn is_aligning(options: &Options) -> bool {
+    need_print_attr(options) && matches!(options.output_mode, OutputMode::Tree | OutputMode::Html)
 }
 
-fn set_blue() {
-    let blue: u8 = 34;
-    set_color(blue);
+fn need_print_attr(options: &Options) -> bool {
+    options.need_protection
+        || options.need_uid
+        || options.need_gid
+        || options.need_size.is_some()
+        || options.need_time
+        || options.need_inode
+        || options.need_device
 }
 
-fn reset() {
-    let esc_char = vec![27];
-    let esc = String::from_utf8(esc_char).unwrap();
-    let reset: u8 = 0;
-    print!("{}[{}m", esc, reset);
+/*
+    writes the events of a `Walker` to `out` in the chosen output mode.
+    `out` is buffered here, call `finish` to flush it. With attributes
+    in tree and HTML outputs, the lines of a tree are held back until it
+    ends so that their attribute columns can be lined up. The trees of -R share one
+    document, which `finish` closes with the report of all of them.
+*/
+pub struct Renderer<W: Write> {
+    out: io::BufWriter<W>,
+    options: Options,
+    prefix: Prefix,
+    tree_count: usize,
+    total: Counter, // of every tree, for the report closing a document
+    held: Vec<HeldLine>,
 }
 
-fn is_file_executable(metadata: &fs::Metadata) -> bool {
-    metadata.permissions().mode() & 0o111 != 0
+// a line of tree or HTML output, already rendered but its attributes
+struct HeldLine {
+    prefix: Vec<u8>,
+    attr: Option<EntryAttr>,
+    name: Vec<u8>, // up to the end of the line
 }
 
-fn need_print_attr() -> bool {
-    SETTING.need_protection
-        || SETTING.need_uid
-        || SETTING.need_gid
-        || SETTING.need_size != 0
-        || SETTING.need_ctime
-        || SETTING.need_inode
-        || SETTING.need_device
+impl<W: Write> Renderer<W> {
+    pub fn new(options: Options, out: W) -> Renderer<W> {
+        Renderer {
+            out: io::BufWriter::new(out),
+            prefix: Prefix::new(&options),
+            options,
+            tree_count: 0,
+            total: Counter::new(),
+            held: Vec::new(),
+        }
+    }
+
+    pub fn render(&mut self, event: &Event) -> io::Result<()> {
+        let attr = match event {
+            Event::Entry { entry, .. } => get_attr(entry, &self.options),
+            _ => Option::None,
+        };
+        if !is_aligning(&self.options) {
+            return self.send_event(event, attr.as_ref());
+        }
+
+        match event {
+            Event::Entry {
+                entry,
+                depth,
+                is_last,
+            } => {
+                self.prefix.set_position(*depth, *is_last);
+                let mut line = HeldLine {
+                    prefix: Vec::new(),
+                    attr,
+                    name: Vec::new(),
+                };
+                self.prefix.print(&mut line.prefix)?;
+                match self.options.output_mode {
+                    OutputMode::Html => send_html_name(&mut line.name, &self.options, entry)?,
+                    _ => send_tree_name(&mut line.name, &self.options, entry)?,
+                }
+                self.held.push(line);
+                Ok(())
+            }
+            // nothing to print for them in tree and HTML outputs
+            Event::DirEnd { .. } => Ok(()),
+            Event::Begin => self.send_event(event, Option::None),
+            Event::End(_) => {
+                let mut widths = Vec::new();
+                for attr in self.held.iter().filter_map(|line| line.attr.as_ref()) {
+                    attr.measure(&mut widths);
+                }
+                for mut line in std::mem::take(&mut self.held) {
+                    self.out.write_all(&line.prefix)?;
+                    if let Some(attr) = &mut line.attr {
+                        attr.align(&widths);
+                        attr.print(&mut self.out)?;
+                    }
+                    self.out.write_all(&line.name)?;
+                }
+                self.send_event(event, Option::None)
+            }
+        }
+    }
+
+    fn send_event(&mut self, event: &Event, attr: Option<&EntryAttr>) -> io::Result<()> {
+        let out = &mut self.out;
+        let options = &self.options;
+        match event {
+            Event::Begin => {
+                self.tree_count += 1;
+                if self.tree_count == 1 {
+                    return send_header(out, options);
+                }
+                // trees of -R are separated within the same document
+                match options.output_mode {
+                    OutputMode::Tree | OutputMode::Html => writeln!(out),
+                    OutputMode::Json => writeln!(out, ","),
+                    OutputMode::Xml => Ok(()),
+                }
+            }
+            Event::Entry {
+                entry,
+                depth,
+                is_last,
+            } => {
+                self.prefix.set_position(*depth, *is_last);
+                send(out, options, &self.prefix, entry, attr, *depth, *is_last)
+            }
+            Event::DirEnd {
+                entry,
+                depth,
+                is_last,
+            } => send_dir_end(out, options, entry, *depth, *is_last),
+            Event::End(counter) => {
+                if options.output_mode == OutputMode::Tree {
+                    send_report(out, options, counter)
+                } else {
+                    self.total.add(counter);
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    // close the document, flush what is buffered and hand back the writer
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.tree_count > 0 && self.options.output_mode != OutputMode::Tree {
+            send_report(&mut self.out, &self.options, &self.total)?;
+        }
+        self.out.into_inner().map_err(|err| err.into_error())
+    }
+}
+
+/*
+    @brief
+        print things that come before the root entry
+*/
+fn send_header(out: &mut dyn Write, options: &Options) -> io::Result<()> {
+    match options.output_mode {
+        OutputMode::Tree => {}
+        OutputMode::Json => writeln!(out, "[")?,
+        OutputMode::Xml => {
+            writeln!(out, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+            writeln!(out, "<tree>")?;
+        }
+        OutputMode::Html => {
+            let title = xml_escape(&options.html_title);
+            writeln!(out, "<!DOCTYPE html>")?;
+            writeln!(out, "<html>")?;
+            writeln!(out, "<head>")?;
+            writeln!(out, " <meta charset=\"UTF-8\">")?;
+            writeln!(out, " <title>{}</title>", title)?;
+            writeln!(out, " <style type=\"text/css\">")?;
+            writeln!(out, "  body {{ font-family: monospace; }}")?;
+            writeln!(out, "  pre {{ line-height: 1.2; }}")?;
+            writeln!(out, "  a {{ text-decoration: none; }}")?;
+            writeln!(out, " </style>")?;
+            writeln!(out, "</head>")?;
+            writeln!(out, "<body>")?;
+            writeln!(out, " <h1>{}</h1>", title)?;
+            write!(out, "<pre>")?;
+        }
+    }
+    Ok(())
+}
+
+/*
+    @brief
+        print one entry. `depth` is 0 for root, `is_last` tells
+        whether it is the last one among its siblings.
+*/
+fn send(
+    out: &mut dyn Write,
+    options: &Options,
+    prefix: &Prefix,
+    entry: &Entry,
+    attr: Option<&EntryAttr>,
+    depth: usize,
+    is_last: bool,
+) -> io::Result<()> {
+    match options.output_mode {
+        OutputMode::Tree => send_tree(out, options, prefix, entry, attr),
+        OutputMode::Json => send_json(out, options, entry, attr, depth, is_last),
+        OutputMode::Xml => send_xml(out, options, entry, attr, depth),
+        OutputMode::Html => send_html(out, options, prefix, entry, attr),
+    }
 }
 
-pub fn send(prefix: &Prefix, entry: &Entry) {
+/*
+    @brief
+        called after all children of a directory have been sent
+*/
+fn send_dir_end(
+    out: &mut dyn Write,
+    options: &Options,
+    entry: &Entry,
+    depth: usize,
+    is_last: bool,
+) -> io::Result<()> {
+    // the entries of unopened dirs were never started
+    if !entry.is_opened() {
+        return Ok(());
+    }
+    match options.output_mode {
+        OutputMode::Tree => {}
+        OutputMode::Json => writeln!(
+            out,
+            "{}]}}{}",
+            indent(depth),
+            if is_last { "" } else { "," }
+        )?,
+        OutputMode::Xml => writeln!(out, "{}</{}>", indent(depth), get_type_name(entry))?,
+        OutputMode::Html => {}
+    }
+    Ok(())
+}
+
+/*
+    @brief
+        print the final report (unless --noreport) and close the document
+*/
+fn send_report(out: &mut dyn Write, options: &Options, counter: &Counter) -> io::Result<()> {
+    match options.output_mode {
+        OutputMode::Tree => {
+            if options.is_needing_report {
+                writeln!(out, "\n{}", get_summary(options, counter))?;
+            }
+        }
+        OutputMode::Json => {
+            if options.is_needing_report {
+                let mut report = format!(
+                    "{{\"type\":\"report\",\"directories\":{},\"files\":{}",
+                    counter.dir_count, counter.file_count
+                );
+                if options.file_limit.is_some() {
+                    report.push_str(&format!(",\"notopened\":{}", counter.not_opened_count));
+                }
+                if counter.error_count > 0 {
+                    report.push_str(&format!(",\"errors\":{}", counter.error_count));
+                }
+                writeln!(out, ",")?;
+                writeln!(out, "{}{}}}", indent(0), report)?;
+            }
+            writeln!(out, "]")?;
+        }
+        OutputMode::Xml => {
+            if options.is_needing_report {
+                writeln!(out, "{}<report>", indent(0))?;
+                writeln!(
+                    out,
+                    "{}<directories>{}</directories>",
+                    indent(1),
+                    counter.dir_count
+                )?;
+                writeln!(out, "{}<files>{}</files>", indent(1), counter.file_count)?;
+                if options.file_limit.is_some() {
+                    writeln!(
+                        out,
+                        "{}<notopened>{}</notopened>",
+                        indent(1),
+                        counter.not_opened_count
+                    )?;
+                }
+                if counter.error_count > 0 {
+                    writeln!(out, "{}<errors>{}</errors>", indent(1), counter.error_count)?;
+                }
+                writeln!(out, "{}</report>", indent(0))?;
+            }
+            writeln!(out, "</tree>")?;
+        }
+        OutputMode::Html => {
+            writeln!(out, "</pre>")?;
+            if options.is_needing_report {
+                writeln!(out, " <hr>")?;
+                writeln!(out, " <p>{}</p>", get_summary(options, counter))?;
+            }
+            writeln!(out, "</body>")?;
+            writeln!(out, "</html>")?;
+        }
+    }
+    Ok(())
+}
+
+// the report line of plain text outputs
+fn get_summary(options: &Options, counter: &Counter) -> String {
+    let mut summary = format!(
+        "{} directories, {} files",
+        counter.dir_count, counter.file_count
+    );
+    if options.file_limit.is_some() {
+        summary.push_str(&format!(
+            ", {} directories not opened",
+            counter.not_opened_count
+        ));
+    }
+    if counter.error_count > 0 {
+        summary.push_str(&format!(", {} errors", counter.error_count));
+    }
+    summary
+}
+
+// the attribute block of the entry itself, none for entries that could not be stat'ed
+fn get_attr(entry: &Entry, options: &Options) -> Option<EntryAttr> {
+    if need_print_attr(options) {
+        entry
+            .get_link_metadata()
+            .map(|metadata| EntryAttr::new(metadata, options))
+    } else {
+        Option::None
+    }
+}
+
+fn send_tree(
+    out: &mut dyn Write,
+    options: &Options,
+    prefix: &Prefix,
+    entry: &Entry,
+    attr: Option<&EntryAttr>,
+) -> io::Result<()> {
     // print prefix
-    prefix.print();
+    prefix.print(out)?;
 
     // print attributes
-    if need_print_attr() {
-        let entry_attr = EntryAttr::new(&entry.get_metadata());
-        entry_attr.print();
+    if let Some(entry_attr) = attr {
+        entry_attr.print(out)?;
+    }
+
+    send_tree_name(out, options, entry)
+}
+
+// the rest of a tree line after the attributes
+fn send_tree_name(out: &mut dyn Write, options: &Options, entry: &Entry) -> io::Result<()> {
+    // print entry, only the name is colored
+    let color = if options.is_color {
+        options.colors.get_color(entry)
+    } else {
+        Option::None
+    };
+    let is_colored = entry.is_ignored() || color.is_some();
+    if entry.is_ignored() {
+        // dimmed under --show-ignored
+        set_color(out, "2")?;
+    }
+    if let Some(code) = color {
+        set_color(out, code)?;
+    }
+    out.write_all(&entry.get_printed_name(options))?;
+    if is_colored {
+        reset(out)?;
+    }
+    out.write_all(&entry.get_link_suffix(options))?;
+    writeln!(out)
+}
+
+/*
+    directories are left open here, `send_dir_end` closes them.
+*/
+fn send_json(
+    out: &mut dyn Write,
+    options: &Options,
+    entry: &Entry,
+    attr: Option<&EntryAttr>,
+    depth: usize,
+    is_last: bool,
+) -> io::Result<()> {
+    let mut line = indent(depth);
+    line.push_str(&format!(
+        "{{\"type\":\"{}\",\"name\":\"{}\"",
+        get_type_name(entry),
+        json_escape(&entry.get_display_name(options))
+    ));
+    if let Some(target) = entry.get_link_target() {
+        line.push_str(&format!(
+            ",\"target\":\"{}\"",
+            json_escape(&target.to_string_lossy())
+        ));
+    }
+    if let Some(note) = entry.get_note() {
+        line.push_str(&format!(",\"error\":\"{}\"", json_escape(&note)));
+    }
+    if let Some(entry_attr) = attr {
+        for (key, value) in entry_attr.get_fields() {
+            match value {
+                AttrValue::Number(number) => line.push_str(&format!(",\"{}\":{}", key, number)),
+                AttrValue::Signed(number) => line.push_str(&format!(",\"{}\":{}", key, number)),
+                AttrValue::Text(text) => {
+                    line.push_str(&format!(",\"{}\":\"{}\"", key, json_escape(text)))
+                }
+            }
+        }
     }
+    if entry.is_opened() {
+        line.push_str(",\"contents\":[");
+    } else {
+        line.push('}');
+        if !is_last {
+            line.push(',');
+        }
+    }
+    writeln!(out, "{}", line)
+}
 
-    // print entry
-    /* todo: use bit flag */
-    if SETTING.is_color {
+/*
+    same indentation lines as the plain tree, with names turned into links
+    of baseHREF plus the entry's path prefix.
+*/
+fn send_html(
+    out: &mut dyn Write,
+    options: &Options,
+    prefix: &Prefix,
+    entry: &Entry,
+    attr: Option<&EntryAttr>,
+) -> io::Result<()> {
+    prefix.print(out)?;
+
+    if let Some(entry_attr) = attr {
+        entry_attr.print(out)?;
+    }
+
+    send_html_name(out, options, entry)
+}
+
+// the rest of an HTML line after the attributes
+fn send_html_name(out: &mut dyn Write, options: &Options, entry: &Entry) -> io::Result<()> {
+    let name = xml_escape(&String::from_utf8_lossy(&entry.get_printed_name(options)));
+    let suffix = xml_escape(&String::from_utf8_lossy(&entry.get_link_suffix(options)));
+    if options.is_no_links {
+        writeln!(out, "{}{}", name, suffix)?;
+    } else {
+        let mut href = options.base_href.trim_end_matches('/').to_string();
+        href.push('/');
+        href.push_str(&url_encode(entry.get_path_prefix().as_os_str().as_bytes()));
         if entry.is_dir() {
-            set_blue();
-        } else if is_file_executable(&entry.get_metadata()) {
-            set_green();
+            href.push('/');
         }
+        writeln!(
+            out,
+            "<a href=\"{}\">{}</a>{}",
+            xml_escape(&href),
+            name,
+            suffix
+        )?;
+    }
+    Ok(())
+}
+
+// percent-encode everything except unreserved characters and '/'
+fn url_encode(raw: &[u8]) -> String {
+    let mut encoded = String::with_capacity(raw.len());
+    for &byte in raw {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+// structured outputs are indented by two spaces per level
+fn indent(depth: usize) -> String {
+    "  ".repeat(depth + 1)
+}
+
+fn json_escape(raw: &str) -> String {
+    let mut escaped = String::with_capacity(raw.len());
+    for c in raw.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/*
+    directories are left open here, `send_dir_end` closes them.
+*/
+fn send_xml(
+    out: &mut dyn Write,
+    options: &Options,
+    entry: &Entry,
+    attr: Option<&EntryAttr>,
+    depth: usize,
+) -> io::Result<()> {
+    let mut line = indent(depth);
+    line.push_str(&format!(
+        "<{} name=\"{}\"",
+        get_type_name(entry),
+        xml_escape(&entry.get_display_name(options))
+    ));
+    if let Some(target) = entry.get_link_target() {
+        line.push_str(&format!(
+            " target=\"{}\"",
+            xml_escape(&target.to_string_lossy())
+        ));
     }
-    entry.print();
-    reset();
+    if let Some(note) = entry.get_note() {
+        line.push_str(&format!(" error=\"{}\"", xml_escape(&note)));
+    }
+    if let Some(entry_attr) = attr {
+        for (key, value) in entry_attr.get_fields() {
+            match value {
+                AttrValue::Number(number) => line.push_str(&format!(" {}=\"{}\"", key, number)),
+                AttrValue::Signed(number) => line.push_str(&format!(" {}=\"{}\"", key, number)),
+                AttrValue::Text(text) => {
+                    line.push_str(&format!(" {}=\"{}\"", key, xml_escape(text)))
+                }
+            }
+        }
+    }
+    if entry.is_opened() {
+        line.push('>');
+    } else {
+        line.push_str("/>");
+    }
+    writeln!(out, "{}", line)
+}
+
+fn xml_escape(raw: &str) -> String {
+    let mut escaped = String::with_capacity(raw.len());
+    for c in raw.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            // control characters are not allowed in XML 1.0, even as references
+            c if (c as u32) < 0x20 && c != '\t' && c != '\n' && c != '\r' => escaped.push('?'),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("&#{};", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[test]
+fn test_fn_json_escape() {
+    assert_eq!(json_escape("plain.rs"), "plain.rs");
+    assert_eq!(json_escape("a\"b\\c"), "a\\\"b\\\\c");
+    assert_eq!(json_escape("tab\there\u{1}"), "tab\\there\\u0001");
+}
+
+#[test]
+fn test_fn_xml_escape() {
+    assert_eq!(xml_escape("plain.rs"), "plain.rs");
+    assert_eq!(
+        xml_escape("<a & 'b'>\""),
+        "&lt;a &amp; &apos;b&apos;&gt;&quot;"
+    );
+    assert_eq!(xml_escape("new\nline\u{1}"), "new&#10;line?");
+}
+
+#[test]
+fn test_fn_url_encode() {
+    assert_eq!(url_encode(b"dir/sub-1/a_b.rs"), "dir/sub-1/a_b.rs");
+    assert_eq!(url_encode(b"my dir/100%"), "my%20dir/100%25");
+    assert_eq!(url_encode("é".as_bytes()), "%C3%A9");
+    assert_eq!(url_encode(b"latin\xe9"), "latin%E9");
+}
+
+#[test]
+fn test_fn_rerun_shares_one_document() {
+    let root = crate::utils::make_test_dir("rerun", &["a/b/"]);
+    let options = Options::new(root.clone())
+        .max_level(Some(1))
+        .rerun(true)
+        .output(OutputMode::Json);
+    let mut renderer = Renderer::new(options.clone(), Vec::new());
+    for event in crate::wtree::Walker::new(options) {
+        renderer.render(&event).unwrap();
+    }
+    let out = String::from_utf8(renderer.finish().unwrap()).unwrap();
+    std::fs::remove_dir_all(&root).unwrap();
+
+    // one header, one report for the three trees, trees separated by a comma
+    assert!(out.starts_with("[\n") && out.ends_with("\n]\n"));
+    assert_eq!(out.matches("\"type\":\"report\"").count(), 1);
+    assert_eq!(out.matches("\n,\n  {\"type\":\"directory\"").count(), 2);
+}
+
+#[test]
+fn test_fn_columns_aligned_across_tree() {
+    let root = crate::utils::make_test_dir("aligned", &["sub/"]);
+    std::fs::write(root.join("sub/large"), [0; 12345]).unwrap();
+    std::fs::write(root.join("small"), [0; 7]).unwrap();
+    let options = Options::new(root.clone())
+        .size(Some(crate::envir::SizeFormat::Bytes))
+        .report(false)
+        .indentation(false);
+    let mut out = Vec::new();
+    crate::wtree::write_tree(options, &mut out).unwrap();
+    std::fs::remove_dir_all(&root).unwrap();
+
+    // the column is as wide as the largest size in the whole tree
+    let out = String::from_utf8(out).unwrap();
+    let files: Vec<&str> = out
+        .lines()
+        .filter(|line| line.ends_with("small") || line.ends_with("large"))
+        .collect();
+    assert_eq!(files, vec!["[    7] small", "[12345] large"]);
+}
+
+#[test]
+fn test_fn_contents_only_for_opened_dirs() {
+    let root = crate::utils::make_test_dir("opened", &["a/", "empty/"]);
+    std::os::unix::fs::symlink("a", root.join("lnk")).unwrap();
+    let render = |options: Options| {
+        let mut out = Vec::new();
+        crate::wtree::write_tree(options.output(OutputMode::Json), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    let out = render(Options::new(root.clone()));
+    let followed = render(Options::new(root.clone()).follow_links(true));
+    std::fs::remove_dir_all(&root).unwrap();
+
+    // an empty dir is read, a link not followed is not
+    assert!(out.contains("{\"type\":\"directory\",\"name\":\"empty\",\"contents\":[\n    ]},"));
+    assert!(out.contains("{\"type\":\"link\",\"name\":\"lnk\",\"target\":\"a\"}\n"));
+    assert!(followed.contains("\"name\":\"lnk\",\"target\":\"a\",\"contents\":["));
 }
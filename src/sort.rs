@@ -0,0 +1,236 @@
+use crate::envir::Options;
+use crate::utils::Entry;
+use std::cmp::Ordering;
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum SortKey {
+    Type, // directories first
+    Name,
+    Version,      // -v
+    Size,         // --sort=size
+    ModifiedTime, // -t
+    ChangeTime,   // -c
+    Extension,    // --sort=extension
+    None,         // -U
+}
+
+/*
+    one key of `--sort`, `-name` sorts descending
+*/
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct SortRule {
+    pub key: SortKey,
+    pub is_descending: bool,
+}
+
+impl SortKey {
+    pub fn from_name(name: &str) -> Option<SortKey> {
+        match name {
+            "type" => Some(SortKey::Type),
+            "name" => Some(SortKey::Name),
+            "version" => Some(SortKey::Version),
+            "size" => Some(SortKey::Size),
+            "mtime" => Some(SortKey::ModifiedTime),
+            "ctime" => Some(SortKey::ChangeTime),
+            "extension" => Some(SortKey::Extension),
+            "none" => Some(SortKey::None),
+            _ => Option::None,
+        }
+    }
+}
+
+/*
+    @brief
+        parse a list like `type,-mtime,name`. `none` anywhere in the
+        list leaves entries unsorted, which is an empty rule list.
+*/
+pub fn parse_sort_rules(list: &str) -> Option<Vec<SortRule>> {
+    let mut rules = Vec::new();
+    for item in list.split(',') {
+        let (is_descending, name) = match item.strip_prefix('-') {
+            Some(name) => (true, name),
+            Option::None => (false, item.strip_prefix('+').unwrap_or(item)),
+        };
+        match SortKey::from_name(name)? {
+            SortKey::None => return Some(Vec::new()),
+            key => rules.push(SortRule { key, is_descending }),
+        }
+    }
+    Some(rules)
+}
+
+/*
+    @brief
+        the one comparator used for sorting: --dirsfirst, then every
+        --sort key in turn, then the name to break ties. -r reverses
+        all of them but --dirsfirst.
+*/
+pub fn compare_entries(a: &Entry, b: &Entry, options: &Options) -> Ordering {
+    let mut order = Ordering::Equal;
+    for rule in &options.sort_rules {
+        order = order.then_with(|| {
+            let by_key = compare(rule.key, a, b, options);
+            if rule.is_descending {
+                by_key.reverse()
+            } else {
+                by_key
+            }
+        });
+    }
+    order = order.then_with(|| compare(SortKey::Name, a, b, options));
+    if options.is_sort_reverse {
+        order = order.reverse();
+    }
+
+    if options.is_dir_first {
+        compare(SortKey::Type, a, b, options).then(order)
+    } else {
+        order
+    }
+}
+
+pub fn compare(key: SortKey, a: &Entry, b: &Entry, options: &Options) -> Ordering {
+    match key {
+        // `true` sorts after `false`
+        SortKey::Type => b.is_dir().cmp(&a.is_dir()),
+        SortKey::Name => a.get_name().cmp(b.get_name()),
+        SortKey::Version => version_cmp(
+            &a.get_name().to_string_lossy(),
+            &b.get_name().to_string_lossy(),
+        ),
+        // entries that could not be stat'ed come first
+        SortKey::Size => {
+            let size = |entry: &Entry| get_metadata(entry, options).map(|data| data.size());
+            size(a).cmp(&size(b))
+        }
+        SortKey::ModifiedTime => {
+            let time = |entry: &Entry| {
+                get_metadata(entry, options).map(|data| (data.mtime(), data.mtime_nsec()))
+            };
+            time(a).cmp(&time(b))
+        }
+        SortKey::ChangeTime => {
+            let time = |entry: &Entry| {
+                get_metadata(entry, options).map(|data| (data.ctime(), data.ctime_nsec()))
+            };
+            time(a).cmp(&time(b))
+        }
+        SortKey::Extension => get_extension(&a.get_name().to_string_lossy())
+            .cmp(get_extension(&b.get_name().to_string_lossy())),
+        SortKey::None => Ordering::Equal,
+    }
+}
+
+// the link itself, as -p, -s and -D show it, unless -l follows it
+fn get_metadata<'a>(entry: &'a Entry, options: &Options) -> Option<&'a Metadata> {
+    if options.is_following_links {
+        entry.get_metadata()
+    } else {
+        entry.get_link_metadata()
+    }
+}
+
+/*
+    @brief
+        the part after the last dot, leading dots of hidden files
+        do not count. files without extension come first.
+*/
+fn get_extension(name: &str) -> &str {
+    match name.trim_start_matches('.').rfind('.') {
+        Some(index) => &name.trim_start_matches('.')[index + 1..],
+        Option::None => "",
+    }
+}
+
+/*
+    @brief
+        compare like `ls -v`, runs of digits are compared by their
+        numeric value, everything else byte by byte
+*/
+pub fn version_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a_rest, mut b_rest) = (a.as_bytes(), b.as_bytes());
+    loop {
+        match (a_rest.first(), b_rest.first()) {
+            (Option::None, Option::None) => break,
+            (Option::None, Some(_)) => return Ordering::Less,
+            (Some(_), Option::None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (a_digits, a_next) = split_digits(a_rest);
+                let (b_digits, b_next) = split_digits(b_rest);
+                let order = a_digits
+                    .len()
+                    .cmp(&b_digits.len())
+                    .then_with(|| a_digits.cmp(b_digits));
+                if order != Ordering::Equal {
+                    return order;
+                }
+                a_rest = a_next;
+                b_rest = b_next;
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(y);
+                }
+                a_rest = &a_rest[1..];
+                b_rest = &b_rest[1..];
+            }
+        }
+    }
+    // equal values such as `01` and `1`
+    a.cmp(b)
+}
+
+// (digits without leading zeros, the rest)
+fn split_digits(raw: &[u8]) -> (&[u8], &[u8]) {
+    let end = raw
+        .iter()
+        .position(|c| !c.is_ascii_digit())
+        .unwrap_or(raw.len());
+    let start = raw[..end].iter().position(|c| *c != b'0').unwrap_or(end);
+    (&raw[start..end], &raw[end..])
+}
+
+#[test]
+fn test_fn_version_cmp() {
+    assert_eq!(version_cmp("file2", "file10"), Ordering::Less);
+    assert_eq!(version_cmp("v1.2.10", "v1.2.9"), Ordering::Greater);
+    assert_eq!(version_cmp("a", "a1"), Ordering::Less);
+    assert_eq!(version_cmp("b1", "a2"), Ordering::Greater);
+    assert_eq!(version_cmp("x007", "x7"), Ordering::Less);
+    assert_eq!(version_cmp("x10y", "x10y"), Ordering::Equal);
+}
+
+#[test]
+fn test_fn_get_extension() {
+    assert_eq!(get_extension("main.rs"), "rs");
+    assert_eq!(get_extension("archive.tar.gz"), "gz");
+    assert_eq!(get_extension(".gitignore"), "");
+    assert_eq!(get_extension(".config.toml"), "toml");
+    assert_eq!(get_extension("Makefile"), "");
+}
+
+#[test]
+fn test_fn_parse_sort_rules() {
+    let rules = parse_sort_rules("type,-mtime,+name").unwrap();
+    assert_eq!(
+        rules,
+        vec![
+            SortRule {
+                key: SortKey::Type,
+                is_descending: false
+            },
+            SortRule {
+                key: SortKey::ModifiedTime,
+                is_descending: true
+            },
+            SortRule {
+                key: SortKey::Name,
+                is_descending: false
+            },
+        ]
+    );
+    assert_eq!(parse_sort_rules("size,none"), Some(Vec::new()));
+    assert_eq!(parse_sort_rules("name,color"), Option::None);
+}
@@ -0,0 +1,84 @@
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::sync::Once;
+
+static TZ_INIT: Once = Once::new();
+
+// POSIX, not exported by the libc crate
+extern "C" {
+    fn tzset();
+}
+
+// six months, as ls and GNU tree count them
+const RECENT_SECONDS: i64 = 6 * 30 * 24 * 60 * 60;
+
+/*
+    @brief
+        the format used without --timefmt: the hour for the last
+        six months, the year otherwise
+*/
+pub fn default_format(seconds: i64, now: i64) -> &'static str {
+    if seconds <= now && now - seconds < RECENT_SECONDS {
+        "%b %e %H:%M"
+    } else {
+        "%b %e  %Y"
+    }
+}
+
+/*
+    @brief
+        format `seconds` since the epoch in the local time zone (TZ
+        or /etc/localtime) with the strftime directives in `format`
+*/
+pub fn format_time(seconds: i64, format: &str) -> String {
+    let format = match CString::new(format) {
+        Ok(format) => format,
+        Err(_) => return String::new(),
+    };
+    if format.as_bytes().is_empty() {
+        return String::new();
+    }
+
+    TZ_INIT.call_once(|| unsafe { tzset() });
+    let time = seconds as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return seconds.to_string();
+    }
+
+    // strftime gives 0 when the buffer is too small, try a larger one
+    let mut buffer: Vec<u8> = vec![0; 128];
+    while buffer.len() <= 4096 {
+        let length = unsafe {
+            libc::strftime(
+                buffer.as_mut_ptr() as *mut c_char,
+                buffer.len(),
+                format.as_ptr(),
+                &tm,
+            )
+        };
+        if length > 0 {
+            buffer.truncate(length);
+            return String::from_utf8_lossy(&buffer).to_string();
+        }
+        buffer.resize(buffer.len() * 4, 0);
+    }
+    String::new()
+}
+
+#[test]
+fn test_fn_default_format() {
+    let now = 1_700_000_000;
+    assert_eq!(default_format(now - 3600, now), "%b %e %H:%M");
+    assert_eq!(default_format(now - RECENT_SECONDS, now), "%b %e  %Y");
+    // future times are shown with the year as well
+    assert_eq!(default_format(now + 3600, now), "%b %e  %Y");
+}
+
+#[test]
+fn test_fn_format_time() {
+    // mid-year, so the year is the same in every time zone
+    assert_eq!(format_time(1_000_000_000, "%Y"), "2001");
+    assert_eq!(format_time(1_000_000_000, "%m/%Y %%"), "09/2001 %");
+    assert_eq!(format_time(0, ""), "");
+}
@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::sync::Mutex;
+
+// looked up once per id, `None` for ids without an entry
+static USER_NAMES: Mutex<Option<HashMap<u32, Option<String>>>> = Mutex::new(Option::None);
+static GROUP_NAMES: Mutex<Option<HashMap<u32, Option<String>>>> = Mutex::new(Option::None);
+
+/*
+    @brief
+        the name of a user from the system user database,
+        `/etc/passwd` or whatever NSS is set up to ask
+*/
+pub fn get_user_name(uid: u32) -> Option<String> {
+    cached(&USER_NAMES, uid, lookup_user)
+}
+
+pub fn get_group_name(gid: u32) -> Option<String> {
+    cached(&GROUP_NAMES, gid, lookup_group)
+}
+
+fn cached(
+    names: &Mutex<Option<HashMap<u32, Option<String>>>>,
+    id: u32,
+    lookup: fn(u32) -> Option<String>,
+) -> Option<String> {
+    let mut names = names.lock().unwrap();
+    names
+        .get_or_insert_with(HashMap::new)
+        .entry(id)
+        .or_insert_with(|| lookup(id))
+        .clone()
+}
+
+fn lookup_user(uid: u32) -> Option<String> {
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+    with_buffer(|buffer| unsafe {
+        libc::getpwuid_r(
+            uid,
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    })
+    .and_then(|_buffer| {
+        if result.is_null() {
+            return Option::None;
+        }
+        Some(
+            unsafe { CStr::from_ptr(passwd.pw_name) }
+                .to_string_lossy()
+                .to_string(),
+        )
+    })
+}
+
+fn lookup_group(gid: u32) -> Option<String> {
+    let mut group: libc::group = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::group = std::ptr::null_mut();
+    with_buffer(|buffer| unsafe {
+        libc::getgrgid_r(
+            gid,
+            &mut group,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    })
+    .and_then(|_buffer| {
+        if result.is_null() {
+            return Option::None;
+        }
+        Some(
+            unsafe { CStr::from_ptr(group.gr_name) }
+                .to_string_lossy()
+                .to_string(),
+        )
+    })
+}
+
+/*
+    @brief
+        run a `get*_r` call with a buffer for its strings, growing it
+        while the call says it is too small. The buffer is given back,
+        the strings the call found point into it.
+*/
+fn with_buffer(mut call: impl FnMut(&mut Vec<c_char>) -> i32) -> Option<Vec<c_char>> {
+    let mut buffer: Vec<c_char> = vec![0; 1024];
+    loop {
+        match call(&mut buffer) {
+            0 => return Some(buffer),
+            libc::ERANGE if buffer.len() < 1 << 20 => {
+                let size = buffer.len() * 2;
+                buffer.resize(size, 0);
+            }
+            _ => return Option::None,
+        }
+    }
+}
+
+#[test]
+fn test_fn_root_names() {
+    // uid and gid 0 are root on every system we run on
+    assert_eq!(get_user_name(0).as_deref(), Some("root"));
+    assert!(get_group_name(0).is_some());
+    // asking again hits the cache
+    assert_eq!(get_user_name(0).as_deref(), Some("root"));
+}
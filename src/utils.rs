@@ -1,11 +1,18 @@
-use crate::envir::{Setting, SETTING};
-use std::cmp::Ordering;
-use std::collections::VecDeque;
+use crate::envir::{Context, NameFormat, Options, SizeFormat, TimeField};
+use crate::gitignore::IgnoreRules;
+use crate::pattern::match_any;
+use crate::sort::compare_entries;
+use crate::timefmt::{default_format, format_time};
+use crate::users::{get_group_name, get_user_name};
+use std::ffi::{OsStr, OsString};
 use std::fs;
 use std::fs::Metadata;
-use std::os::unix::fs::{MetadataExt, PermissionsExt};
-use std::path::PathBuf;
-use std::time::SystemTime;
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 enum PrefixMode {
     FileTree,
@@ -28,16 +35,20 @@ fn get_tree_prefix(prefix_type: TreePrefix) -> String {
     }
 }
 
+/*
+    the indentation lines in front of an entry, worked out from
+    whether the entry and each of its ancestors is the last sibling
+*/
 pub struct Prefix {
-    prefix: VecDeque<String>,
+    levels: Vec<bool>,
     mode: PrefixMode,
 }
 
 impl Prefix {
-    pub fn new() -> Prefix {
+    pub fn new(options: &Options) -> Prefix {
         Prefix {
-            prefix: VecDeque::new(),
-            mode: if SETTING.is_no_indentation {
+            levels: Vec::new(),
+            mode: if options.is_no_indentation {
                 PrefixMode::None
             } else {
                 PrefixMode::FileTree
@@ -45,147 +56,377 @@ impl Prefix {
         }
     }
 
-    pub fn set_init_value(&mut self, init_prefix: String) {
-        match self.mode {
-            PrefixMode::FileTree => self.prefix.push_back(init_prefix),
-            _ => {}
+    // move to the entry about to be printed, the root entry is at depth 0
+    pub fn set_position(&mut self, depth: usize, is_last: bool) {
+        self.levels.truncate(depth.saturating_sub(1));
+        if depth > 0 {
+            self.levels.push(is_last);
         }
     }
 
-    pub fn add_prefix(&mut self, is_begin: bool, is_last: bool, is_dir: bool) {
-        match &self.mode {
-            PrefixMode::FileTree => {
-                if is_dir {
-                    self.prefix.pop_back();
-                    if is_last {
-                        self.prefix
-                            .push_back(get_tree_prefix(TreePrefix::Tab).clone());
-                        self.prefix
-                            .push_back(get_tree_prefix(TreePrefix::EndLeaf).clone());
-                    } else {
-                        self.prefix
-                            .push_back(get_tree_prefix(TreePrefix::SubDirTab).clone());
-                        self.prefix
-                            .push_back(get_tree_prefix(TreePrefix::Leaf).clone());
-                    }
+    pub fn print(&self, out: &mut dyn Write) -> io::Result<()> {
+        if let PrefixMode::None = self.mode {
+            return Ok(());
+        }
+        if let Some((is_last, ancestors)) = self.levels.split_last() {
+            for is_ancestor_last in ancestors {
+                if *is_ancestor_last {
+                    write!(out, "{}", get_tree_prefix(TreePrefix::Tab))?;
                 } else {
-                    if is_last {
-                        self.prefix.pop_back();
-                        self.prefix
-                            .push_back(get_tree_prefix(TreePrefix::EndLeaf).clone());
-                    } else if is_begin {
-                        self.prefix.pop_back();
-                        self.prefix
-                            .push_back(get_tree_prefix(TreePrefix::Leaf).clone());
-                    }
+                    write!(out, "{}", get_tree_prefix(TreePrefix::SubDirTab))?;
                 }
             }
-            PrefixMode::None => {
-                // no operation is needed here
+            if *is_last {
+                write!(out, "{}", get_tree_prefix(TreePrefix::EndLeaf))?;
+            } else {
+                write!(out, "{}", get_tree_prefix(TreePrefix::Leaf))?;
             }
         }
+        Ok(())
     }
+}
 
-    pub fn remove_prefix(&mut self, is_next_last: bool, is_dir: bool) {
-        match &self.mode {
-            PrefixMode::FileTree => {
-                if is_dir {
-                    self.prefix.pop_back();
-                    self.prefix.pop_back();
-                    if is_next_last {
-                        self.prefix
-                            .push_back(get_tree_prefix(TreePrefix::EndLeaf).clone())
-                    } else {
-                        self.prefix
-                            .push_back(get_tree_prefix(TreePrefix::Leaf).clone())
-                    }
-                } else {
-                    // no operation is needed here
-                }
-            }
-            PrefixMode::None => {
-                // no operation is needed here
-            }
+// the kind of a file, as the entry itself is, not followed
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FileType {
+    Dir,
+    File,
+    Symlink,
+    Fifo,
+    Socket,
+    CharDevice,
+    BlockDevice,
+    Door, // Solaris only
+}
+
+impl FileType {
+    pub fn from_metadata(metadata: &Metadata) -> FileType {
+        let file_type = metadata.file_type();
+        if file_type.is_symlink() {
+            FileType::Symlink
+        } else if file_type.is_dir() {
+            FileType::Dir
+        } else if file_type.is_fifo() {
+            FileType::Fifo
+        } else if file_type.is_socket() {
+            FileType::Socket
+        } else if file_type.is_char_device() {
+            FileType::CharDevice
+        } else if file_type.is_block_device() {
+            FileType::BlockDevice
+        } else if metadata.mode() & 0o170000 == 0o150000 {
+            FileType::Door
+        } else {
+            FileType::File
+        }
+    }
+
+    // the first character of a protection string
+    pub fn get_mode_char(self) -> char {
+        match self {
+            FileType::Dir => 'd',
+            FileType::File => '-',
+            FileType::Symlink => 'l',
+            FileType::Fifo => 'p',
+            FileType::Socket => 's',
+            FileType::CharDevice => 'c',
+            FileType::BlockDevice => 'b',
+            FileType::Door => 'D',
         }
     }
 
-    pub fn print(&self) {
-        for item in &self.prefix {
-            print!("{}", item);
+    // element name in structured outputs
+    pub fn get_name(self) -> &'static str {
+        match self {
+            FileType::Dir => "directory",
+            FileType::File => "file",
+            FileType::Symlink => "link",
+            FileType::Fifo => "fifo",
+            FileType::Socket => "socket",
+            FileType::CharDevice => "char",
+            FileType::BlockDevice => "block",
+            FileType::Door => "door",
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Entry {
     path: PathBuf,
     is_dir: bool,
+    file_type: Option<FileType>, // of the entry itself, not followed
     is_visible: bool,
     path_prefix: PathBuf,
-    entry_name: String,
-    is_empty: bool, // identify fake entry
+    entry_name: OsString,
+    metadata: Option<Metadata>,      // followed, taken once in `new`
+    link_metadata: Option<Metadata>, // the entry itself, not followed
+    error: Option<ErrorKind>,        // failed to stat it, or to open it as a dir
+    is_pattern_matched: bool,        // itself or a parent dir matched -P under --matchdirs
+    is_ignored: bool,                // ignored by git, under --gitignore
+    ignore_rules: Option<Arc<IgnoreRules>>, // rules of the dirs above, read up to the parent
+    link_target: Option<PathBuf>,    // only for symbolic links
+    is_recursive: bool,              // a followed link leading back to an ancestor
+    exceeded_count: Option<usize>,   // entries of a dir not opened due to --filelimit
+    is_opened: bool,                 // its entries were read by the walker
+    ancestors: Vec<(u64, u64)>,      // (dev, inode) of parent dirs, under -l
 }
 
 impl Entry {
-    pub fn new(path: PathBuf) -> Entry {
-        if path.exists() {
-            Entry {
-                is_dir: path.is_dir(),
-                is_visible: Entry::visible_or_not(match path.file_name() {
-                    Some(path) => path.to_str().unwrap(),
-                    Option::None => "/",
-                }),
-                path_prefix: {
-                    path.strip_prefix(Setting::get_root_prefix())
-                        .unwrap()
-                        .to_path_buf()
-                },
-                entry_name: String::from(match path.file_name() {
-                    Some(path) => path.to_str().unwrap(),
-                    Option::None => "/",
-                }),
-                path,
-                is_empty: false,
-            }
-        } else {
-            // fake empty entry
-            Entry {
-                is_dir: false,
-                is_visible: false,
-                path_prefix: path.clone(),
-                path,
-                entry_name: "".to_string(),
-                is_empty: true,
+    /*
+        @brief
+            stat the path once, everything later reads the cached metadata.
+            an entry that cannot be stat'ed has none and keeps the error.
+    */
+    pub fn new(path: PathBuf, context: &Context) -> Entry {
+        let (link_metadata, metadata, error) = match path.symlink_metadata() {
+            Ok(link_metadata) if link_metadata.file_type().is_symlink() => {
+                // broken links fall back to the link itself
+                let metadata = path.metadata().unwrap_or_else(|_| link_metadata.clone());
+                (Some(link_metadata), Some(metadata), Option::None)
             }
+            Ok(link_metadata) => (
+                Some(link_metadata.clone()),
+                Some(link_metadata),
+                Option::None,
+            ),
+            Err(err) => (Option::None, Option::None, Some(err.kind())),
+        };
+        Entry::with_metadata(path, link_metadata, metadata, error, context)
+    }
+
+    /*
+        @brief
+            an item `read_dir` failed to give. Its name is lost with the
+            error, it is shown as `?` in `dir` to keep the error in sight.
+    */
+    fn new_unreadable(dir: &Path, kind: ErrorKind, context: &Context) -> Entry {
+        Entry::with_metadata(
+            dir.join("?"),
+            Option::None,
+            Option::None,
+            Some(kind),
+            context,
+        )
+    }
+
+    fn with_metadata(
+        path: PathBuf,
+        link_metadata: Option<Metadata>,
+        metadata: Option<Metadata>,
+        error: Option<ErrorKind>,
+        context: &Context,
+    ) -> Entry {
+        let file_type = link_metadata.as_ref().map(FileType::from_metadata);
+        Entry {
+            is_dir: metadata.as_ref().is_some_and(|data| data.is_dir()),
+            file_type,
+            is_visible: Entry::visible_or_not(path.file_name().unwrap_or(path.as_os_str())),
+            path_prefix: {
+                path.strip_prefix(&context.root_prefix)
+                    .unwrap()
+                    .to_path_buf()
+            },
+            entry_name: path.file_name().unwrap_or(path.as_os_str()).to_os_string(),
+            link_target: if file_type == Some(FileType::Symlink) {
+                fs::read_link(&path).ok()
+            } else {
+                Option::None
+            },
+            path,
+            metadata,
+            link_metadata,
+            error,
+            is_pattern_matched: false,
+            is_ignored: false,
+            ignore_rules: Option::None,
+            is_recursive: false,
+            exceeded_count: Option::None,
+            is_opened: false,
+            ancestors: Vec::new(),
         }
     }
 
-    pub fn get_metadata(&self) -> Metadata {
-        if self.path.exists() {
-            self.path.metadata().unwrap()
-        } else {
-            fs::metadata("/").unwrap()
+    pub fn new_root(path: PathBuf, context: &Context) -> Entry {
+        let mut root = Entry::new(path, context);
+        // a root below the top one (-R) is named by its whole path
+        if root.path_prefix.components().count() > 1 {
+            root.entry_name = root.path_prefix.clone().into_os_string();
+        }
+        if context.options.use_gitignore {
+            root.ignore_rules = Some(IgnoreRules::for_root(&root.path));
+        }
+        root
+    }
+
+    // what the entry points to, or the link itself when it is broken
+    pub fn get_metadata(&self) -> Option<&Metadata> {
+        self.metadata.as_ref()
+    }
+
+    // the entry itself, a link is not followed
+    pub fn get_link_metadata(&self) -> Option<&Metadata> {
+        self.link_metadata.as_ref()
+    }
+
+    pub fn get_file_type(&self) -> Option<FileType> {
+        self.file_type
+    }
+
+    pub fn get_error(&self) -> Option<ErrorKind> {
+        self.error
+    }
+
+    pub fn set_error(&mut self, kind: ErrorKind) {
+        self.error = Some(kind);
+    }
+
+    pub fn get_name(&self) -> &OsStr {
+        &self.entry_name
+    }
+
+    // the name with -f applied, lossy for outputs that need UTF-8
+    pub fn get_display_name(&self, options: &Options) -> String {
+        self.get_full_name(options).to_string_lossy().to_string()
+    }
+
+    // display name with -Q, -q and -N applied, as bytes
+    pub fn get_printed_name(&self, options: &Options) -> Vec<u8> {
+        format_name(&self.get_full_name(options), options)
+    }
+
+    fn get_full_name(&self, options: &Options) -> OsString {
+        let mut full_name = OsString::new();
+        if options.is_full_path {
+            full_name.push(&self.path_prefix);
+        }
+        full_name.push(&self.entry_name);
+        full_name
+    }
+
+    pub fn get_path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    pub fn get_path_prefix(&self) -> &PathBuf {
+        &self.path_prefix
+    }
+
+    /*
+        @brief
+            what follows the name: the type character of -F, ` -> target`
+            for symbolic links, and a note when the entry was not opened
+    */
+    pub fn get_link_suffix(&self, options: &Options) -> Vec<u8> {
+        let mut suffix = Vec::new();
+        if options.is_classify {
+            if let Some(character) = self.get_classify_char() {
+                suffix.push(character as u8);
+            }
         }
+        if let Some(target) = &self.link_target {
+            suffix.extend_from_slice(b" -> ");
+            suffix.extend(format_name(target.as_os_str(), options));
+        }
+        if let Some(note) = self.get_note() {
+            suffix.extend(format!("  [{}]", note).into_bytes());
+        }
+        suffix
     }
 
-    pub fn print(&self) {
-        let mut entry_name_to_print = self.entry_name.clone();
-        if SETTING.is_full_path {
-            entry_name_to_print
-                .insert_str(0, self.path_prefix.to_str().expect("not utf-8 filename"));
+    // -F, as ls does
+    fn get_classify_char(&self) -> Option<char> {
+        match self.file_type? {
+            FileType::Dir => Some('/'),
+            FileType::Symlink => Some('@'),
+            FileType::Socket => Some('='),
+            FileType::Fifo => Some('|'),
+            FileType::Door => Some('>'),
+            FileType::File if self.link_metadata.as_ref()?.mode() & 0o111 != 0 => Some('*'),
+            _ => Option::None,
         }
-        if SETTING.is_quote {
-            println!("{:?}", entry_name_to_print);
+    }
+
+    // why an entry could not be read or a directory was not opened
+    pub fn get_note(&self) -> Option<String> {
+        if let Some(kind) = self.error {
+            match kind {
+                ErrorKind::PermissionDenied => Some("permission denied".to_string()),
+                _ if self.is_dir => Some("error opening dir".to_string()),
+                _ => Some("error reading entry".to_string()),
+            }
+        } else if self.is_recursive {
+            Some("recursive, not followed".to_string())
         } else {
-            println!("{}", entry_name_to_print);
+            self.exceeded_count
+                .map(|count| format!("{} entries exceeds filelimit, not opened", count))
         }
     }
 
+    // only opened dirs have contents in structured outputs
+    pub fn is_opened(&self) -> bool {
+        self.is_opened
+    }
+
+    pub fn set_opened(&mut self) {
+        self.is_opened = true;
+    }
+
+    pub fn set_exceeded_count(&mut self, count: usize) {
+        self.exceeded_count = Some(count);
+    }
+
+    pub fn get_link_target(&self) -> Option<&PathBuf> {
+        self.link_target.as_ref()
+    }
+
+    pub fn print(&self, out: &mut dyn Write, options: &Options) -> io::Result<()> {
+        out.write_all(&self.get_printed_name(options))?;
+        out.write_all(&self.get_link_suffix(options))?;
+        writeln!(out)
+    }
+
     pub fn is_dir(&self) -> bool {
         self.is_dir
     }
 
-    pub fn traverse(&self) -> Result<Vec<Entry>, std::io::Error> {
+    pub fn is_symlink(&self) -> bool {
+        self.file_type == Some(FileType::Symlink)
+    }
+
+    // a link whose target is missing keeps the metadata of the link
+    pub fn is_broken_link(&self) -> bool {
+        self.is_symlink()
+            && self
+                .metadata
+                .as_ref()
+                .is_some_and(|data| data.file_type().is_symlink())
+    }
+
+    /*
+        links to directories are only followed with -l,
+        and never when they lead back to an ancestor.
+        with -x, mount points are listed but not opened.
+    */
+    pub fn can_descend(&self, context: &Context) -> bool {
+        self.is_dir
+            && !self.is_recursive
+            && self.exceeded_count.is_none()
+            && (!self.is_symlink() || context.options.is_following_links)
+            && (!context.options.is_one_filesystem
+                || self
+                    .get_metadata()
+                    .is_some_and(|data| data.dev() == context.root_device))
+    }
+
+    pub fn is_pattern_matched(&self) -> bool {
+        self.is_pattern_matched
+    }
+
+    pub fn is_ignored(&self) -> bool {
+        self.is_ignored
+    }
+
+    pub fn traverse(&self, context: &Context) -> Result<Vec<Entry>, std::io::Error> {
         // check
         if !self.is_dir {
             return Err(std::io::Error::new(
@@ -195,214 +436,607 @@ impl Entry {
         }
 
         // make entry list
-        let mut path_list: Vec<Entry> = match fs::read_dir(&self.path) {
-            Ok(list) => list,
-            Err(e) => return Err(e),
-        }
-        .map(|item| -> Entry {
-            match item {
-                Ok(sth) => {
-                    if sth.path().exists() {
-                        Entry::new(sth.path())
-                    } else {
-                        Entry::new(PathBuf::new())
-                    }
-                }
-                _ => Entry::new(PathBuf::new()), // not correct, need to return error
-            }
-        })
-        .filter(Entry::filter)
-        .collect();
+        let options = &context.options;
+        // the .gitignore of a dir is only read once it is opened
+        let rules = self
+            .ignore_rules
+            .as_ref()
+            .map(|rules| IgnoreRules::descend(rules, &self.path));
+        let mut path_list: Vec<Entry> = fs::read_dir(&self.path)?
+            .map(|item| match item {
+                Ok(item) => Entry::new(item.path(), context),
+                Err(err) => Entry::new_unreadable(&self.path, err.kind(), context),
+            })
+            // removed since read_dir, not worth an error
+            .filter(|entry| entry.error != Some(ErrorKind::NotFound))
+            .map(|entry| self.mark_pattern_matched(entry, options))
+            .map(|entry| self.mark_ignored(entry, rules.as_ref()))
+            .map(|entry| self.mark_recursive(entry, options))
+            .filter(|entry| Entry::filter(entry, options))
+            .collect();
 
-        if SETTING.is_file_number_limited && path_list.len() > Setting::get_max_files() as usize {
-            return Ok(Vec::new());
-        }
-
-        if SETTING.is_unsort {
-            return Ok(path_list);
-        }
-        if SETTING.is_dir_first {
-            path_list.sort_by(Entry::dir_first)
-        }
-        if SETTING.is_sort_alphanumerically {
-            path_list.sort_by_key(|entry| entry.entry_name.clone())
-        }
-        if SETTING.is_sort_mod_time {
-            path_list.sort_by(Entry::sort_by_modified_time);
-        }
-        if SETTING.is_sort_reverse {
-            path_list.reverse();
+        // -U keeps the directory order, --dirsfirst included
+        if !options.sort_rules.is_empty() {
+            path_list.sort_by(|a, b| compare_entries(a, b, options));
         }
 
         Ok(path_list)
     }
 
-    fn filter(item: &Entry) -> bool {
-        // delete file that not exist
-        if item.is_empty {
+    fn filter(item: &Entry, options: &Options) -> bool {
+        // -a
+        if !options.is_all && !item.is_visible {
             return false;
         }
 
-        // -a
-        if !SETTING.is_all && !item.is_visible {
+        // --gitignore
+        if item.is_ignored && !options.is_showing_ignored {
             return false;
         }
 
         // -d
-        if SETTING.is_dir_only && !item.is_dir {
+        if options.is_dir_only && !item.is_dir {
             return false;
         }
 
-        // pattern (-I or -P)
-        if let Some((method, pattern, ignore_case)) = Setting::get_pattern() {
-            if ignore_case {
-                return (method == 'I') ^ (item.entry_name.find(pattern.as_str()) == Option::None);
-            } else {
-                return (method == 'I')
-                    ^ (item.entry_name.to_lowercase().find(pattern.as_str()) == Option::None);
+        // -I, excludes win over includes
+        if match_any(
+            &options.exclude_patterns,
+            &item.entry_name.to_string_lossy(),
+            options.pattern_ignore_case,
+        ) {
+            return false;
+        }
+
+        // -P, only files need to match, and not at all inside a matched dir
+        if !item.is_dir
+            && !item.is_pattern_matched
+            && !options.include_patterns.is_empty()
+            && !match_any(
+                &options.include_patterns,
+                &item.entry_name.to_string_lossy(),
+                options.pattern_ignore_case,
+            )
+        {
+            return false;
+        }
+
+        true
+    }
+
+    /*
+        with --matchdirs, a directory matching -P gets all of its contents listed
+    */
+    fn mark_pattern_matched(&self, mut child: Entry, options: &Options) -> Entry {
+        child.is_pattern_matched = self.is_pattern_matched
+            || (options.is_match_dirs
+                && child.is_dir
+                && match_any(
+                    &options.include_patterns,
+                    &child.entry_name.to_string_lossy(),
+                    options.pattern_ignore_case,
+                ));
+        child
+    }
+
+    /*
+        with --gitignore, check the child against `rules`, those of this
+        dir included. Everything below an ignored dir is ignored as well.
+    */
+    fn mark_ignored(&self, mut child: Entry, rules: Option<&Arc<IgnoreRules>>) -> Entry {
+        if let Some(rules) = rules {
+            child.is_ignored = self.is_ignored || rules.is_ignored(&child.path, child.is_dir);
+            if child.is_dir {
+                child.ignore_rules = Some(rules.clone());
             }
         }
+        child
+    }
+
+    /*
+        with -l, remember the directories on the way down so that
+        a link pointing back to one of them is not followed
+    */
+    fn mark_recursive(&self, mut child: Entry, options: &Options) -> Entry {
+        if options.is_following_links && child.is_dir {
+            child.ancestors = self.ancestors.clone();
+            child.ancestors.push(self.get_dir_id());
+            child.is_recursive = child.ancestors.contains(&child.get_dir_id());
+        }
+        child
+    }
 
-        return true;
+    fn get_dir_id(&self) -> (u64, u64) {
+        self.get_metadata()
+            .map_or((0, 0), |metadata| (metadata.dev(), metadata.ino()))
     }
 
-    fn visible_or_not(name: &str) -> bool {
-        if let Some(character) = name.get(0..1) {
-            !(character == ".")
-        } else {
-            false
+    fn visible_or_not(name: &OsStr) -> bool {
+        name.as_bytes().first().is_some_and(|byte| *byte != b'.')
+    }
+}
+
+/*
+    @brief
+        the bytes of a name as printed in text output. Characters that
+        can't be printed and bytes that are not UTF-8 are escaped as
+        `\ooo`, shown as `?` with -q, or kept as they are with -N.
+        -Q wraps the name in double quotes.
+*/
+fn format_name(name: &OsStr, options: &Options) -> Vec<u8> {
+    let bytes = name.as_bytes();
+    let mut formatted = Vec::with_capacity(bytes.len() + 2);
+    if options.is_quote {
+        formatted.push(b'"');
+    }
+    for chunk in bytes.utf8_chunks() {
+        for character in chunk.valid().chars() {
+            let mut buffer = [0; 4];
+            let encoded = character.encode_utf8(&mut buffer).as_bytes();
+            if options.is_quote && (character == '"' || character == '\\') {
+                formatted.push(b'\\');
+                formatted.extend_from_slice(encoded);
+            } else if character.is_control() {
+                push_unprintable(&mut formatted, encoded, options.name_format);
+            } else {
+                formatted.extend_from_slice(encoded);
+            }
         }
+        push_unprintable(&mut formatted, chunk.invalid(), options.name_format);
+    }
+    if options.is_quote {
+        formatted.push(b'"');
     }
+    formatted
+}
 
-    // sort functions
-    fn dir_first(a: &Entry, b: &Entry) -> Ordering {
-        if !(a.is_dir ^ b.is_dir) {
-            return Ordering::Equal;
-        } else if !a.is_dir {
-            return Ordering::Greater;
-        } else {
-            return Ordering::Less;
+fn push_unprintable(formatted: &mut Vec<u8>, bytes: &[u8], format: NameFormat) {
+    match format {
+        NameFormat::Escaped => {
+            for byte in bytes {
+                formatted.extend(format!("\\{:03o}", byte).into_bytes());
+            }
         }
+        NameFormat::Question => formatted.extend(std::iter::repeat_n(b'?', bytes.len())),
+        NameFormat::Raw => formatted.extend_from_slice(bytes),
     }
+}
 
-    fn sort_by_modified_time(a: &Entry, b: &Entry) -> Ordering {
-        let a_time = match a.get_metadata().modified() {
-            Ok(time) => time,
-            Err(_e) => SystemTime::now(),
-        };
-        let b_time = match b.get_metadata().modified() {
-            Ok(time) => time,
-            Err(_e) => SystemTime::now(),
-        };
-        a_time.cmp(&b_time)
+// typed value of one attribute column, used by structured outputs
+pub enum AttrValue {
+    Number(u64),
+    Signed(i64), // times before the epoch are negative
+    Text(String),
+}
+
+// the columns of the attribute block, in the order they are printed
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AttrColumn {
+    Protection, // -p
+    User,       // -u
+    Group,      // -g
+    Size,       // -s, -h, --si
+    Time,       // -D
+    Inode,      // --inodes
+    Device,     // --device
+}
+
+impl AttrColumn {
+    // numbers line up on the right, names and dates on the left
+    fn is_right_aligned(self) -> bool {
+        matches!(
+            self,
+            AttrColumn::Size | AttrColumn::Inode | AttrColumn::Device
+        )
     }
 }
 
 pub struct EntryAttr {
-    content: String,
+    columns: Vec<(AttrColumn, String)>,
+    fields: Vec<(&'static str, AttrValue)>,
 }
 
 impl EntryAttr {
-    pub fn new(metadata: &Metadata) -> EntryAttr {
-        let mut cont = String::new();
-        if SETTING.need_protection {
-            EntryAttr::setup_protection(metadata, &mut cont);
+    pub fn new(metadata: &Metadata, options: &Options) -> EntryAttr {
+        let mut attr = EntryAttr {
+            columns: Vec::new(),
+            fields: Vec::new(),
+        };
+        if options.need_protection {
+            attr.setup_protection(metadata);
         }
-        if SETTING.need_uid {
-            EntryAttr::setup_uid(metadata, &mut cont);
+        if options.need_uid {
+            attr.setup_uid(metadata, options);
         }
-        if SETTING.need_gid {
-            EntryAttr::setup_gid(metadata, &mut cont);
+        if options.need_gid {
+            attr.setup_gid(metadata, options);
         }
-        if SETTING.need_size != 0 {
-            EntryAttr::setup_size(metadata, &mut cont);
+        if let Some(format) = options.need_size {
+            attr.setup_size(metadata, format);
         }
-        if SETTING.need_ctime {
-            EntryAttr::setup_time(metadata, &mut cont);
+        if options.need_time {
+            attr.setup_time(metadata, options);
         }
-        if SETTING.need_inode {
-            EntryAttr::setup_inode(metadata, &mut cont);
+        if options.need_inode {
+            attr.setup_inode(metadata);
         }
-        if SETTING.need_device {
-            EntryAttr::setup_device(metadata, &mut cont);
+        if options.need_device {
+            attr.setup_device(metadata);
         }
 
-        EntryAttr { content: cont }
+        attr
     }
 
-    pub fn print(&self) {
-        print!("[{:}] ", self.content);
+    pub fn print(&self, out: &mut dyn Write) -> io::Result<()> {
+        let texts: Vec<&str> = self.columns.iter().map(|(_, text)| text.as_str()).collect();
+        write!(out, "[{:}] ", texts.join(" "))
     }
 
-    fn setup_protection(metadata: &Metadata, content: &mut String) {
-        let flags_bit = vec![
-            0b0100_000_000,
-            0b0010_000_000,
-            0b0001_000_000,
-            0b0000_100_000,
-            0b0000_010_000,
-            0b0000_001_000,
-            0b0000_000_100,
-            0b0000_000_010,
-            0b0000_000_001,
+    /*
+        @brief
+            widen `widths` to fit the columns of this entry. Every entry
+            printed with the same options has the same columns.
+    */
+    pub fn measure(&self, widths: &mut Vec<usize>) {
+        if widths.len() < self.columns.len() {
+            widths.resize(self.columns.len(), 0);
+        }
+        for ((_, text), width) in self.columns.iter().zip(widths.iter_mut()) {
+            *width = (*width).max(text.chars().count());
+        }
+    }
+
+    // pad every column to the width `measure` found
+    pub fn align(&mut self, widths: &[usize]) {
+        for ((column, text), width) in self.columns.iter_mut().zip(widths) {
+            *text = if column.is_right_aligned() {
+                format!("{:>width$}", text, width = width)
+            } else {
+                format!("{:<width$}", text, width = width)
+            };
+        }
+    }
+
+    pub fn get_fields(&self) -> &Vec<(&'static str, AttrValue)> {
+        &self.fields
+    }
+
+    fn setup_protection(&mut self, metadata: &Metadata) {
+        let flags_bit = [
+            0o400, 0o200, 0o100, // owner
+            0o040, 0o020, 0o010, // group
+            0o004, 0o002, 0o001, // other
         ];
         let mode = metadata.permissions().mode();
         let flags_char = "rwxrwxrwx";
 
-        let mut protection = String::from(if metadata.is_dir() { "d" } else { "-" });
+        let mut protection = String::new();
+        protection.push(FileType::from_metadata(metadata).get_mode_char());
 
-        for i in 0..9 {
-            if mode & flags_bit[i] == 0 {
+        for (bit, flag) in flags_bit.iter().zip(flags_char.chars()) {
+            if mode & bit == 0 {
                 protection.push('-');
             } else {
-                protection.push(flags_char.chars().nth(i).unwrap());
+                protection.push(flag);
+            }
+        }
+
+        // setuid, setgid and sticky take the place of an `x`
+        let special_bits = [(0o4000, 3, 's'), (0o2000, 6, 's'), (0o1000, 9, 't')];
+        for (bit, index, flag) in special_bits {
+            if mode & bit != 0 {
+                let is_executable = protection.as_bytes()[index] == b'x';
+                let flag = if is_executable {
+                    flag
+                } else {
+                    flag.to_ascii_uppercase()
+                };
+                protection.replace_range(index..index + 1, &flag.to_string());
             }
         }
-        content.push_str(&protection);
+        self.columns
+            .push((AttrColumn::Protection, protection.clone()));
+        self.fields
+            .push(("mode", AttrValue::Text(format!("{:04o}", mode & 0o7777))));
+        self.fields.push(("prot", AttrValue::Text(protection)));
     }
 
-    fn setup_uid(metadata: &Metadata, content: &mut String) {
-        content.push_str(&format!(" {:}", metadata.uid()))
+    /*
+        the owner by name, or by number when it has none or
+        with --numeric-ids
+    */
+    fn setup_uid(&mut self, metadata: &Metadata, options: &Options) {
+        self.fields
+            .push(("uid", AttrValue::Number(metadata.uid().into())));
+        let name = if options.is_numeric_ids {
+            Option::None
+        } else {
+            get_user_name(metadata.uid())
+        };
+        match name {
+            Some(name) => {
+                self.columns.push((AttrColumn::User, name.clone()));
+                self.fields.push(("user", AttrValue::Text(name)));
+            }
+            Option::None => self
+                .columns
+                .push((AttrColumn::User, metadata.uid().to_string())),
+        }
     }
 
-    fn setup_gid(metadata: &Metadata, content: &mut String) {
-        content.push_str(&format!(" {:}", metadata.gid()))
+    fn setup_gid(&mut self, metadata: &Metadata, options: &Options) {
+        self.fields
+            .push(("gid", AttrValue::Number(metadata.gid().into())));
+        let name = if options.is_numeric_ids {
+            Option::None
+        } else {
+            get_group_name(metadata.gid())
+        };
+        match name {
+            Some(name) => {
+                self.columns.push((AttrColumn::Group, name.clone()));
+                self.fields.push(("group", AttrValue::Text(name)));
+            }
+            Option::None => self
+                .columns
+                .push((AttrColumn::Group, metadata.gid().to_string())),
+        }
     }
 
-    fn setup_size(metadata: &Metadata, content: &mut String) {
+    fn setup_size(&mut self, metadata: &Metadata, format: SizeFormat) {
         let raw_size = metadata.size();
-        match SETTING.need_size {
-            1 => content.push_str(&format!(" {:}", raw_size)),
-            2 => content.push_str(&EntryAttr::convert_size(raw_size, 1024)),
-            3 => content.push_str(&EntryAttr::convert_size(raw_size, 1000)),
-            _ => panic!(),
-        }
+        let text = match format {
+            SizeFormat::Bytes => raw_size.to_string(),
+            SizeFormat::Binary => EntryAttr::convert_size(raw_size, 1024),
+            SizeFormat::Si => EntryAttr::convert_size(raw_size, 1000),
+        };
+        self.columns.push((AttrColumn::Size, text));
+        // structured outputs always carry the exact byte count
+        self.fields.push(("size", AttrValue::Number(raw_size)));
     }
 
     /*
-        show it in UNIX timestamp style.
+        the time chosen by -c or --time, formatted in local time.
+        `-` when the file system does not keep a birth time.
     */
-    fn setup_time(metadata: &Metadata, content: &mut String) {
-        content.push_str(&format!(" {:}", metadata.ctime()))
+    fn setup_time(&mut self, metadata: &Metadata, options: &Options) {
+        let seconds = match options.time_field {
+            TimeField::Modified => Some(metadata.mtime()),
+            TimeField::Accessed => Some(metadata.atime()),
+            TimeField::Changed => Some(metadata.ctime()),
+            TimeField::Birth => {
+                metadata
+                    .created()
+                    .ok()
+                    .map(|time| match time.duration_since(UNIX_EPOCH) {
+                        Ok(duration) => duration.as_secs() as i64,
+                        // rounded down, as mtime and the like are
+                        Err(err) => -(err.duration().as_secs_f64().ceil() as i64),
+                    })
+            }
+        };
+        let seconds = match seconds {
+            Some(seconds) => seconds,
+            Option::None => {
+                self.columns.push((AttrColumn::Time, "-".to_string()));
+                return;
+            }
+        };
+        let format = match &options.time_format {
+            Some(format) => format.as_str(),
+            Option::None => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |duration| duration.as_secs() as i64);
+                default_format(seconds, now)
+            }
+        };
+        self.columns
+            .push((AttrColumn::Time, format_time(seconds, format)));
+        // structured outputs carry the seconds since the epoch
+        self.fields.push(("time", AttrValue::Signed(seconds)));
     }
 
-    fn setup_inode(metadata: &Metadata, content: &mut String) {
-        content.push_str(&format!(" {:}", metadata.ino()))
+    fn setup_inode(&mut self, metadata: &Metadata) {
+        self.columns
+            .push((AttrColumn::Inode, metadata.ino().to_string()));
+        self.fields
+            .push(("inode", AttrValue::Number(metadata.ino())));
     }
 
-    fn setup_device(metadata: &Metadata, content: &mut String) {
-        content.push_str(&format!(" {:}", metadata.dev()))
+    fn setup_device(&mut self, metadata: &Metadata) {
+        self.columns
+            .push((AttrColumn::Device, metadata.dev().to_string()));
+        self.fields.push(("dev", AttrValue::Number(metadata.dev())));
     }
 
+    /*
+        always five wide, with one decimal below ten: ` 512B`, `4.5K`, ` 12M`
+    */
     fn convert_size(raw_size: u64, base: u16) -> String {
-        let unit = vec!["B", "K", "M", "G", "T", "P"];
+        let unit = ["B", "K", "M", "G", "T", "P", "E"];
         let mut size: f64 = raw_size as f64;
         let mut count: usize = 0;
         while size > base.into() {
             size /= base as f64;
             count += 1;
         }
-        size = (size * 10.0).round() / 10.0;
-        format!(" {:4}{}", size, unit[count])
+        if count > 0 && size < 9.95 {
+            format!("{:4.1}{}", size, unit[count])
+        } else {
+            format!("{:4.0}{}", size, unit[count])
+        }
     }
 }
+
+// a fresh directory under the system temp dir holding `files`, names ending with '/' are dirs
+#[cfg(test)]
+pub(crate) fn make_test_dir(name: &str, files: &[&str]) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("wtree-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+    for file in files {
+        if file.ends_with('/') {
+            fs::create_dir_all(root.join(file)).unwrap();
+        } else {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+    }
+    root
+}
+
+#[cfg(test)]
+fn traverse_names(options: Options) -> Vec<String> {
+    let context = Context::new(options);
+    let root = Entry::new_root(context.options.root.clone(), &context);
+    root.traverse(&context)
+        .unwrap()
+        .iter()
+        .map(|entry| entry.get_name().to_string_lossy().to_string())
+        .collect()
+}
+
+#[test]
+fn test_fn_traverse_hidden_and_order() {
+    let root = make_test_dir("hidden", &["b.rs", "a.txt", ".hidden", "c/"]);
+    assert_eq!(
+        traverse_names(Options::new(root.clone())),
+        vec!["a.txt", "b.rs", "c"]
+    );
+    assert_eq!(
+        traverse_names(Options::new(root.clone()).all(true).dirs_first(true)),
+        vec!["c", ".hidden", "a.txt", "b.rs"]
+    );
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn test_fn_traverse_patterns() {
+    let root = make_test_dir("patterns", &["main.rs", "lib.rs", "notes.md", "src/"]);
+    assert_eq!(
+        traverse_names(Options::new(root.clone()).include("*.rs")),
+        vec!["lib.rs", "main.rs", "src"]
+    );
+    assert_eq!(
+        traverse_names(Options::new(root.clone()).include("*.rs").exclude("lib*")),
+        vec!["main.rs", "src"]
+    );
+    assert_eq!(
+        traverse_names(Options::new(root.clone()).dirs_only(true)),
+        vec!["src"]
+    );
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn test_fn_traverse_symlink_loop() {
+    let root = make_test_dir("loop", &["sub/"]);
+    std::os::unix::fs::symlink("..", root.join("sub/up")).unwrap();
+    let context = Context::new(Options::new(root.clone()).follow_links(true));
+    let root_entry = Entry::new_root(root.clone(), &context);
+    let sub = root_entry.traverse(&context).unwrap().remove(0);
+    assert!(sub.can_descend(&context));
+    let up = sub.traverse(&context).unwrap().remove(0);
+    assert!(up.is_symlink() && up.is_dir());
+    assert!(!up.can_descend(&context));
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn test_fn_format_name() {
+    let name = OsStr::from_bytes(b"caf\xe9\tlist");
+    let options = Options::new(PathBuf::from("."));
+    assert_eq!(format_name(name, &options), b"caf\\351\\011list");
+    let options = options.name_format(NameFormat::Question);
+    assert_eq!(format_name(name, &options), b"caf??list");
+    let options = options.name_format(NameFormat::Raw);
+    assert_eq!(format_name(name, &options), name.as_bytes());
+    let options = options.name_format(NameFormat::Escaped).quote(true);
+    assert_eq!(
+        format_name(OsStr::new("say \"hi\""), &options),
+        b"\"say \\\"hi\\\"\""
+    );
+}
+
+#[test]
+fn test_fn_convert_size() {
+    assert_eq!(EntryAttr::convert_size(512, 1024), " 512B");
+    assert_eq!(EntryAttr::convert_size(1024, 1024), "1024B");
+    assert_eq!(EntryAttr::convert_size(4608, 1024), " 4.5K");
+    assert_eq!(EntryAttr::convert_size(12 << 20, 1024), "  12M");
+    assert_eq!(EntryAttr::convert_size(1_500_000, 1000), " 1.5M");
+    assert_eq!(EntryAttr::convert_size(u64::MAX, 1024), "  16E");
+}
+
+#[test]
+fn test_fn_file_type_and_protection() {
+    let root = make_test_dir("types", &["sub/", "suid"]);
+    std::os::unix::fs::symlink("sub", root.join("link")).unwrap();
+    fs::set_permissions(root.join("suid"), fs::Permissions::from_mode(0o4754)).unwrap();
+    fs::set_permissions(root.join("sub"), fs::Permissions::from_mode(0o1776)).unwrap();
+
+    let options = Options::new(root.clone()).protection(true).classify(true);
+    let context = Context::new(options.clone());
+    let entries = Entry::new_root(root.clone(), &context)
+        .traverse(&context)
+        .unwrap();
+    let protections: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            let attr = EntryAttr::new(entry.get_link_metadata().unwrap(), &options);
+            attr.columns[0].1.clone()
+        })
+        .collect();
+    assert_eq!(protections, vec!["lrwxrwxrwx", "drwxrwxrwT", "-rwsr-xr--"]);
+    let types: Vec<Option<FileType>> = entries.iter().map(Entry::get_file_type).collect();
+    assert_eq!(
+        types,
+        vec![
+            Some(FileType::Symlink),
+            Some(FileType::Dir),
+            Some(FileType::File)
+        ]
+    );
+    assert_eq!(entries[0].get_link_suffix(&options), b"@ -> sub");
+    assert_eq!(entries[1].get_link_suffix(&options), b"/");
+    assert_eq!(entries[2].get_link_suffix(&options), b"*");
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn test_fn_time_before_epoch() {
+    let root = make_test_dir("epoch", &["old"]);
+    let file = fs::File::options()
+        .write(true)
+        .open(root.join("old"))
+        .unwrap();
+    file.set_modified(UNIX_EPOCH - std::time::Duration::from_secs(3600))
+        .unwrap();
+    let metadata = fs::metadata(root.join("old")).unwrap();
+    fs::remove_dir_all(root).unwrap();
+
+    let options = Options::new(PathBuf::from(".")).date(true);
+    let attr = EntryAttr::new(&metadata, &options);
+    match attr.get_fields().iter().find(|(key, _)| *key == "time") {
+        Some((_, AttrValue::Signed(seconds))) => assert_eq!(*seconds, -3600),
+        _ => panic!("the time is a signed number"),
+    }
+}
+
+#[test]
+fn test_fn_traverse_sort_links_by_size() {
+    let root = make_test_dir("sort-links", &[]);
+    fs::write(root.join("small"), [0; 10]).unwrap();
+    fs::write(root.join("big"), [0; 100]).unwrap();
+    std::os::unix::fs::symlink("big", root.join("lnk")).unwrap();
+    let options = Options::new(root.clone()).sort(crate::sort::parse_sort_rules("size").unwrap());
+    // the link is as long as its target path, as -s shows it
+    assert_eq!(traverse_names(options.clone()), vec!["lnk", "small", "big"]);
+    assert_eq!(
+        traverse_names(options.follow_links(true)),
+        vec!["small", "big", "lnk"]
+    );
+    fs::remove_dir_all(root).unwrap();
+}
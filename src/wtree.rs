@@ -1,17 +1,28 @@
+use crate::envir::{Context, Options};
+use crate::parallel::Pool;
+use crate::print::Renderer;
 use crate::utils::*;
-extern crate lazy_static;
-use crate::envir::{Setting, SETTING};
-use crate::print::send;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, ErrorKind, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
 
-struct Counter {
-    file_count: u32,
-    dir_count: u32,
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Counter {
+    pub file_count: u32,
+    pub dir_count: u32,
+    pub not_opened_count: u32, // --filelimit
+    pub error_count: u32,
 }
 impl Counter {
     pub fn new() -> Counter {
         Counter {
             file_count: 0,
             dir_count: 0,
+            not_opened_count: 0,
+            error_count: 0,
         }
     }
 
@@ -23,65 +34,635 @@ impl Counter {
         }
     }
 
-    pub fn print_counter(&self) {
-        if SETTING.is_needing_report {
-            println!(
-                "\n{} directories, {} files",
-                self.dir_count, self.file_count,
-            );
+    pub fn increase_not_opened(&mut self) {
+        self.not_opened_count += 1;
+    }
+
+    pub fn increase_error(&mut self) {
+        self.error_count += 1;
+    }
+
+    // the total over the trees of -R
+    pub fn add(&mut self, other: &Counter) {
+        self.file_count += other.file_count;
+        self.dir_count += other.dir_count;
+        self.not_opened_count += other.not_opened_count;
+        self.error_count += other.error_count;
+    }
+}
+
+/*
+    what the walker meets, in printing order. Every directory entry,
+    the root entry included, is closed by a `DirEnd`.
+*/
+#[derive(Clone, Debug)]
+pub enum Event {
+    Begin, // a tree starts, -R gives more than one
+    Entry {
+        entry: Entry,
+        depth: usize, // 0 for the root entry
+        is_last: bool,
+    },
+    DirEnd {
+        entry: Entry,
+        depth: usize,
+        is_last: bool,
+    },
+    End(Counter), // a tree is done
+}
+
+// one entry with everything listed below it
+#[derive(Debug)]
+pub struct Node {
+    pub entry: Entry,
+    pub children: Vec<Node>,
+}
+
+// a directory whose entries are being handed out
+struct Frame {
+    dir: Entry,
+    depth: usize,
+    is_last: bool,
+    children: std::vec::IntoIter<Entry>,
+}
+
+/*
+    walks the tree under the root of `Options` as an iterator of
+    `Event`s. With -R, directories at the level limit are walked
+    again as trees of their own.
+*/
+pub struct Walker {
+    context: Arc<Context>,
+    pending: VecDeque<PathBuf>,
+    root: Option<Entry>, // announced by `Begin`, not sent yet
+    stack: Vec<Frame>,
+    pruned: HashMap<PathBuf, Listing>, // read by --prune, not taken yet
+    counter: Counter,
+    is_tree_done: bool,
+    pool: Option<Arc<Pool>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl Walker {
+    pub fn new(options: Options) -> Walker {
+        let context = Arc::new(Context::new(options));
+        let mut walker = Walker {
+            context: context.clone(),
+            pending: VecDeque::new(),
+            root: Option::None,
+            stack: Vec::new(),
+            pruned: HashMap::new(),
+            counter: Counter::new(),
+            is_tree_done: false,
+            pool: Option::None,
+            workers: Vec::new(),
+        };
+        walker.pending.push_back(context.options.get_root());
+
+        // workers read ahead, while the walker keeps the usual order
+        if context.options.threads > 1 {
+            let pool = Arc::new(Pool::new(context.clone()));
+            for _ in 0..context.options.threads {
+                let pool = pool.clone();
+                walker.workers.push(thread::spawn(move || pool.work()));
+            }
+            walker.pool = Some(pool);
+        }
+
+        walker
+    }
+
+    /*
+        @brief
+            collect the walk into one `Node` per tree
+    */
+    pub fn into_trees(self) -> Vec<Node> {
+        let mut trees = Vec::new();
+        let mut stack: Vec<Node> = Vec::new();
+        for event in self {
+            match event {
+                Event::Entry { entry, depth, .. } => {
+                    let node = Node {
+                        entry,
+                        children: Vec::new(),
+                    };
+                    // the ones a `DirEnd` will close
+                    if node.entry.is_dir() {
+                        stack.push(node);
+                    } else if let Some(parent) = stack.last_mut() {
+                        parent.children.push(node);
+                    } else if depth == 0 {
+                        trees.push(node);
+                    }
+                }
+                Event::DirEnd { .. } => {
+                    let node = stack.pop().expect("unbalanced DirEnd");
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(node),
+                        Option::None => trees.push(node),
+                    }
+                }
+                Event::Begin | Event::End(_) => {}
+            }
+        }
+        trees
+    }
+
+    /*
+        @brief
+            read the entries of a directory whose own event is not sent
+            yet, so that it can still be marked as not opened. `depth` is
+            the depth of the entries (1 for the children of the root entry).
+    */
+    fn open_dir(&mut self, dir: &mut Entry, depth: usize) -> Vec<Entry> {
+        if !dir.can_descend(&self.context) {
+            return Vec::new();
+        }
+        if is_beyond_level(&self.context.options, depth) {
+            if self.context.options.is_rerun {
+                self.pending.push_back(dir.get_path().clone());
+            }
+            return Vec::new();
+        }
+        let listing = match &self.pool {
+            Some(pool) => pool.read(dir, depth),
+            Option::None => match self.pruned.remove(dir.get_path()) {
+                Some(listing) => listing,
+                Option::None => read_listing(&self.context, dir, depth, &mut self.pruned),
+            },
+        };
+        match listing {
+            Listing::Entries(path_list) => {
+                dir.set_opened();
+                path_list
+            }
+            Listing::Exceeded(count) => {
+                dir.set_exceeded_count(count);
+                self.counter.increase_not_opened();
+                Vec::new()
+            }
+            Listing::Failed(kind) => {
+                dir.set_error(kind);
+                Vec::new()
+            }
+        }
+    }
+
+    // the event of a dir is sent after its entries are read
+    fn enter(&mut self, mut dir: Entry, depth: usize, is_last: bool) -> Event {
+        let children = self.open_dir(&mut dir, depth + 1);
+        if dir.get_error().is_some() {
+            self.counter.increase_error();
+        }
+        self.stack.push(Frame {
+            dir: dir.clone(),
+            depth,
+            is_last,
+            children: children.into_iter(),
+        });
+        Event::Entry {
+            entry: dir,
+            depth,
+            is_last,
         }
     }
 }
 
-pub fn print_tree() -> std::io::Result<()> {
-    let mut counter = Counter::new();
-    let mut prefix = Prefix::new();
-    let root_entry = Entry::new(Setting::get_root());
-    send(&prefix, &root_entry);
-    prefix.set_init_value("├── ".to_string());
-    print_subdir(&root_entry, &mut prefix, &mut counter, Setting::get_level())?;
-    counter.print_counter();
+impl Iterator for Walker {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        if let Some(root) = self.root.take() {
+            // a root that is no directory has nothing to close
+            if !root.is_dir() {
+                if root.get_error().is_some() {
+                    self.counter.increase_error();
+                }
+                self.is_tree_done = true;
+                return Some(Event::Entry {
+                    entry: root,
+                    depth: 0,
+                    is_last: true,
+                });
+            }
+            if let Some(pool) = &self.pool {
+                pool.start(&root);
+            }
+            return Some(self.enter(root, 0, true));
+        }
+
+        if let Some(frame) = self.stack.last_mut() {
+            let depth = frame.depth + 1;
+            let event = match frame.children.next() {
+                Some(entry) => {
+                    // identify the last item
+                    let is_last = frame.children.len() == 0;
+                    self.counter.increase_counter(entry.is_dir());
+                    if entry.is_dir() {
+                        self.enter(entry, depth, is_last)
+                    } else {
+                        if entry.get_error().is_some() {
+                            self.counter.increase_error();
+                        }
+                        Event::Entry {
+                            entry,
+                            depth,
+                            is_last,
+                        }
+                    }
+                }
+                Option::None => {
+                    let frame = self.stack.pop().unwrap();
+                    self.is_tree_done = self.stack.is_empty();
+                    Event::DirEnd {
+                        entry: frame.dir,
+                        depth: frame.depth,
+                        is_last: frame.is_last,
+                    }
+                }
+            };
+            return Some(event);
+        }
+
+        if self.is_tree_done {
+            self.is_tree_done = false;
+            return Some(Event::End(std::mem::take(&mut self.counter)));
+        }
+
+        let root = self.pending.pop_front()?;
+        self.root = Some(Entry::new_root(root, &self.context));
+        Some(Event::Begin)
+    }
+}
 
-    Ok(())
+impl Drop for Walker {
+    fn drop(&mut self) {
+        if let Some(pool) = &self.pool {
+            pool.finish();
+        }
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
 }
 
-fn print_subdir(
-    root: &Entry,
-    prefix: &mut Prefix,
-    counter: &mut Counter,
-    level_limit: i32,
-) -> std::io::Result<()> {
-    if level_limit == 0 {
-        return Ok(());
+pub fn is_beyond_level(options: &Options, depth: usize) -> bool {
+    match options.max_level {
+        Some(max_level) => depth > max_level,
+        Option::None => false,
     }
-    let path_list = match root.traverse() {
+}
+
+// what reading one directory turned out to be
+pub enum Listing {
+    Entries(Vec<Entry>),
+    Exceeded(usize), // --filelimit
+    Failed(ErrorKind),
+}
+
+/*
+    @brief
+        read, filter and sort the entries of a directory. It only looks
+        at the file system, so workers of `Pool` can run it as well.
+        --prune reads the whole subtree here, the listings of the dirs
+        it keeps are left in `pruned` so that none is read twice.
+*/
+pub fn read_listing(
+    context: &Context,
+    dir: &Entry,
+    depth: usize,
+    pruned: &mut HashMap<PathBuf, Listing>,
+) -> Listing {
+    let mut path_list = match dir.traverse(context) {
         Ok(list) => list,
-        Err(_) => return Ok(()),
+        Err(err) => return Listing::Failed(err.kind()),
     };
+    if let Some(file_limit) = context.options.file_limit {
+        if path_list.len() > file_limit {
+            return Listing::Exceeded(path_list.len());
+        }
+    }
+    if context.options.is_prune {
+        path_list.retain(|entry| is_worth_showing(context, entry, depth, pruned));
+    }
 
-    let file_num = path_list.len();
+    Listing::Entries(path_list)
+}
 
-    let mut iter_cnt = 0;
-    for path in path_list {
-        iter_cnt += 1;
+/*
+    @brief
+        for --prune, check whether an entry still has something
+        to show within the level limit after filtering. `depth` is
+        the depth of the entry itself.
+*/
+fn is_worth_showing(
+    context: &Context,
+    entry: &Entry,
+    depth: usize,
+    pruned: &mut HashMap<PathBuf, Listing>,
+) -> bool {
+    if !entry.can_descend(context) || entry.is_pattern_matched() {
+        return true;
+    }
+    if is_beyond_level(&context.options, depth + 1) {
+        return false;
+    }
+    let listing = read_listing(context, entry, depth + 1, pruned);
+    let is_worth = match &listing {
+        Listing::Entries(path_list) => !path_list.is_empty(),
+        // kept, so that the walker notes them
+        Listing::Exceeded(_) | Listing::Failed(_) => true,
+    };
+    if is_worth {
+        pruned.insert(entry.get_path().clone(), listing);
+    }
+    is_worth
+}
+
+/*
+    @brief
+        walk with `options` and print everything to stdout,
+        or to the file given by -o
+    @return
+        the number of entries that could not be read
+*/
+pub fn print_tree(options: Options) -> std::io::Result<u32> {
+    match &options.output_file {
+        Some(path) => write_tree(options.clone(), File::create(path)?),
+        Option::None => write_tree(options, io::stdout().lock()),
+    }
+}
+
+pub fn write_tree<W: Write>(options: Options, out: W) -> std::io::Result<u32> {
+    write_walk(Walker::new(options.clone()), options, out)
+}
+
+fn write_walk<W: Write>(walker: Walker, options: Options, out: W) -> std::io::Result<u32> {
+    let mut renderer = Renderer::new(options, out);
+    let mut error_count = 0;
+    for event in walker {
+        if let Event::End(counter) = &event {
+            error_count += counter.error_count;
+        }
+        renderer.render(&event)?;
+    }
+    renderer.finish()?;
+
+    Ok(error_count)
+}
+
+#[cfg(test)]
+fn describe(event: &Event) -> String {
+    match event {
+        Event::Begin => String::from("begin"),
+        Event::Entry {
+            entry,
+            depth,
+            is_last,
+        } => format!("{:?} {} {}", entry.get_name(), depth, is_last),
+        Event::DirEnd { entry, .. } => format!("end {:?}", entry.get_name()),
+        Event::End(counter) => format!("{} {}", counter.dir_count, counter.file_count),
+    }
+}
+
+#[test]
+fn test_fn_walker_events() {
+    let root = make_test_dir("events", &["a/", "a/x", "b"]);
+    let events: Vec<String> = Walker::new(Options::new(root.clone()))
+        .map(|event| describe(&event))
+        .collect();
+    let name = format!("{:?}", root.file_name().unwrap());
+    assert_eq!(
+        events,
+        vec![
+            String::from("begin"),
+            format!("{} 0 true", name),
+            String::from("\"a\" 1 false"),
+            String::from("\"x\" 2 true"),
+            String::from("end \"a\""),
+            String::from("\"b\" 1 true"),
+            format!("end {}", name),
+            String::from("1 2"),
+        ]
+    );
+
+    let trees = Walker::new(Options::new(root.clone()).dirs_first(true)).into_trees();
+    std::fs::remove_dir_all(&root).unwrap();
+    assert_eq!(trees.len(), 1);
+    let names: Vec<_> = trees[0]
+        .children
+        .iter()
+        .map(|node| (node.entry.get_name().to_os_string(), node.children.len()))
+        .collect();
+    assert_eq!(names, vec![("a".into(), 1), ("b".into(), 0)]);
+}
+
+#[test]
+fn test_fn_walker_file_root() {
+    let root = make_test_dir("file-root", &["file"]);
+    let options = Options::new(root.join("file"));
+    let events: Vec<String> = Walker::new(options.clone())
+        .map(|event| describe(&event))
+        .collect();
+    assert_eq!(events, vec!["begin", "\"file\" 0 true", "0 0"]);
+    assert_eq!(Walker::new(options.clone()).into_trees().len(), 1);
+
+    let mut out = Vec::new();
+    write_tree(options.output(crate::envir::OutputMode::Json), &mut out).unwrap();
+    std::fs::remove_dir_all(&root).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "[\n  {\"type\":\"file\",\"name\":\"file\"}\n,\n  \
+         {\"type\":\"report\",\"directories\":0,\"files\":0}\n]\n"
+    );
+}
 
-        // identify the last item
-        prefix.add_prefix(iter_cnt == 1, iter_cnt == file_num, false);
+#[test]
+fn test_fn_walker_root_name() {
+    // the working directory is the root of the crate
+    let cwd = std::env::current_dir().unwrap();
+    let event = Walker::new(Options::new(PathBuf::from(".")))
+        .nth(1)
+        .unwrap();
+    match event {
+        Event::Entry { entry, .. } => assert_eq!(Some(entry.get_name()), cwd.file_name()),
+        _ => panic!("the root entry comes after Begin"),
+    }
+}
+
+#[test]
+fn test_fn_unreadable_dir() {
+    use std::os::unix::fs::PermissionsExt;
+    // root reads every directory, there is nothing to check then
+    if unsafe { libc::geteuid() } == 0 {
+        return;
+    }
+    let root = make_test_dir("unreadable", &["locked/", "locked/f", "open/", "open/g"]);
+    let set_mode = |mode| {
+        std::fs::set_permissions(root.join("locked"), std::fs::Permissions::from_mode(mode))
+            .unwrap()
+    };
+    set_mode(0o000);
+
+    let mut out = Vec::new();
+    let error_count = write_tree(Options::new(root.clone()).report(false), &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    // --prune keeps it, since it can't tell whether there is anything in it
+    let trees = Walker::new(Options::new(root.clone()).prune(true).include("g")).into_trees();
+    let counter = Walker::new(Options::new(root.clone())).last();
+    set_mode(0o755);
+    std::fs::remove_dir_all(&root).unwrap();
+
+    assert_eq!(error_count, 1);
+    assert!(out.contains("├── locked  [permission denied]\n"));
+    assert_eq!(trees[0].children.len(), 2);
+    match counter {
+        Some(Event::End(counter)) => assert_eq!(counter.error_count, 1),
+        _ => panic!("the walk ends with End"),
+    }
+}
+
+#[test]
+fn test_fn_failed_listing() {
+    let root = make_test_dir("failed", &["gone/", "locked/", "locked/f", "open/g"]);
+    let context = Context::new(Options::new(root.clone()).prune(true));
+    let gone = Entry::new(root.join("gone"), &context);
+    std::fs::remove_dir(root.join("gone")).unwrap();
+    let mut pruned = HashMap::new();
+    let listing = read_listing(&context, &gone, 1, &mut pruned);
+    assert!(matches!(listing, Listing::Failed(ErrorKind::NotFound)));
+    // --prune keeps it, since it can't tell whether there is anything in it
+    assert!(is_worth_showing(&context, &gone, 0, &mut pruned));
+    assert!(matches!(
+        pruned.get(gone.get_path()),
+        Some(Listing::Failed(_))
+    ));
+
+    // a listing that failed the way an unreadable dir does, even for root
+    let options = Options::new(root.clone()).report(false);
+    let mut walker = Walker::new(options.clone());
+    walker.pruned.insert(
+        root.join("locked"),
+        Listing::Failed(ErrorKind::PermissionDenied),
+    );
+    let mut out = Vec::new();
+    let error_count = write_walk(walker, options, &mut out).unwrap();
+    std::fs::remove_dir_all(&root).unwrap();
+
+    assert_eq!(error_count, 1);
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains("├── locked  [permission denied]\n└── open\n"));
+}
+
+// the tree printed under `options`, without its root line
+#[cfg(test)]
+fn render_below_root(options: Options) -> String {
+    let mut out = Vec::new();
+    write_tree(options.report(false), &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    out.split_once('\n').unwrap().1.to_string()
+}
+
+#[test]
+fn test_fn_prune() {
+    let root = make_test_dir(
+        "prune",
+        &[
+            "a/b/", "a/b/x.rs", "empty/", "c/y.txt", "c/big/1/", "c/big/2/", "c/big/3/",
+            "c/big/4/", "c/big/5/", "c/big/6/", "top.rs",
+        ],
+    );
+    let options = Options::new(root.clone()).include("*.rs").prune(true);
+    let full = render_below_root(options.clone());
+    let limited = render_below_root(options.clone().max_level(Some(2)));
+    let exceeded = render_below_root(options.file_limit(Some(5)));
+    std::fs::remove_dir_all(&root).unwrap();
+
+    assert_eq!(
+        full,
+        "├── a\n│\u{a0}\u{a0} └── b\n│\u{a0}\u{a0}     └── x.rs\n└── top.rs\n"
+    );
+    // nothing within the level limit below a
+    assert_eq!(limited, "└── top.rs\n");
+    // what is in big is not known, it is kept with its note
+    assert_eq!(
+        exceeded,
+        "├── a\n│\u{a0}\u{a0} └── b\n│\u{a0}\u{a0}     └── x.rs\n├── c\n\
+         │\u{a0}\u{a0} └── big  [6 entries exceeds filelimit, not opened]\n└── top.rs\n"
+    );
+}
 
-        send(&prefix, &path);
-        counter.increase_counter(path.is_dir());
+#[test]
+fn test_fn_prune_match_dirs() {
+    let root = make_test_dir(
+        "matchdirs",
+        &["m/", "m/z.txt", "m/n/", "other/", "other/w.txt"],
+    );
+    let options = Options::new(root.clone()).include("m").match_dirs(true);
+    let kept = render_below_root(options.clone());
+    let pruned = render_below_root(options.prune(true));
+    std::fs::remove_dir_all(&root).unwrap();
 
-        // is dir
-        if path.is_dir() {
-            prefix.add_prefix(false, iter_cnt == file_num, true);
+    // everything in a matched dir is listed
+    assert_eq!(
+        kept,
+        "├── m\n│\u{a0}\u{a0} ├── n\n│\u{a0}\u{a0} └── z.txt\n└── other\n"
+    );
+    assert_eq!(pruned, "└── m\n    ├── n\n    └── z.txt\n");
+}
+
+#[test]
+fn test_fn_gitignore() {
+    let root = make_test_dir(
+        "gitignore",
+        &[
+            ".git/",
+            "a.log",
+            "b.txt",
+            "sub/keep.log",
+            "sub/other.log",
+            "sub/build/o",
+            "ign/x.log",
+        ],
+    );
+    std::fs::write(root.join(".gitignore"), "*.log\nign/\n").unwrap();
+    std::fs::write(root.join("sub/.gitignore"), "!keep.log\nbuild/\n").unwrap();
+    let out = render_below_root(Options::new(root.clone()).gitignore(true));
+    std::fs::remove_dir_all(&root).unwrap();
 
-            // recursive
-            print_subdir(&path, prefix, counter, level_limit - 1)?;
+    // the rules of a dir apply below it only
+    assert_eq!(out, "├── b.txt\n└── sub\n    └── keep.log\n");
+}
 
-            // recover prefix
-            prefix.remove_prefix(iter_cnt + 1 == file_num, true);
+#[test]
+fn test_fn_threads_same_output() {
+    // more directories than the workers may read ahead
+    let mut paths = Vec::new();
+    for dir in 0..40 {
+        for sub in 0..4 {
+            paths.push(format!("d{}/s{}/f{}.rs", dir, sub, sub));
+            paths.push(format!("d{}/s{}/g.txt", dir, sub));
         }
+        paths.push(format!("d{}/empty/", dir));
     }
+    let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+    let root = make_test_dir("threads", &paths);
 
-    Ok(())
+    let render = |options: Options| {
+        let mut out = Vec::new();
+        write_tree(options, &mut out).unwrap();
+        out
+    };
+    let variants = [
+        Options::new(root.clone()),
+        Options::new(root.clone()).include("*.rs").prune(true),
+        Options::new(root.clone()).max_level(Some(1)).rerun(true),
+    ];
+    for options in variants {
+        let serial = render(options.clone());
+        for threads in [2, 4, 8] {
+            assert_eq!(serial, render(options.clone().threads(threads)));
+        }
+    }
+    std::fs::remove_dir_all(&root).unwrap();
 }